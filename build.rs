@@ -0,0 +1,17 @@
+use std::process::Command;
+
+fn main() {
+    // Builds from a crates.io tarball have no git checkout, the commit is optional.
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=WATCHMAN_GIT_COMMIT={}", commit);
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
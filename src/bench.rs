@@ -0,0 +1,138 @@
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::state::Persist;
+use crate::state::State;
+use crate::state::StateError;
+use crate::state::StateTrait;
+
+/// Startup times of one process over all runs.
+#[derive(Debug)]
+pub struct Timing {
+    pub name: String,
+    pub runs: Vec<Duration>,
+}
+
+impl Timing {
+    pub fn average(&self) -> Duration {
+        sum(&self.runs) / self.runs.len().max(1) as u32
+    }
+
+    pub fn min(&self) -> Duration {
+        self.runs.iter().min().copied().unwrap_or_default()
+    }
+
+    pub fn max(&self) -> Duration {
+        self.runs.iter().max().copied().unwrap_or_default()
+    }
+}
+
+fn sum(durations: &[Duration]) -> Duration {
+    durations.iter().sum()
+}
+
+/// Timings of every process, slowest first, and the time it took to bring up everything.
+#[derive(Debug)]
+pub struct Report {
+    pub timings: Vec<Timing>,
+    pub totals: Vec<Duration>,
+}
+
+impl Report {
+    pub fn average_total(&self) -> Duration {
+        sum(&self.totals) / self.totals.len().max(1) as u32
+    }
+}
+
+/// Starts the processes one by one in dependency order, timing each until it's ready.
+fn start_all(
+    state: &mut State,
+    indexes: &[usize],
+    config: &Config,
+    timeout: Duration,
+    timings: &mut [Timing],
+    persist: &mut Persist,
+) -> Result<Duration, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let mut pending: Vec<usize> = (0..indexes.len()).collect();
+
+    while !pending.is_empty() {
+        let mut waiting = vec![];
+        for i in pending.iter().copied() {
+            let index = indexes[i];
+            if state.check_dependencies(index).is_err() {
+                waiting.push(i);
+                continue;
+            }
+
+            let process_started = Instant::now();
+            state.run_one(index, config, persist)?;
+            state[index].wait_until_ready(timeout)?;
+            timings[i].runs.push(process_started.elapsed());
+        }
+
+        if waiting.len() == pending.len() {
+            // None of them can start, the error says why.
+            state.check_dependencies(indexes[waiting[0]])?;
+            return Err(Box::new(StateError::new("Dependencies can't be started")));
+        }
+        pending = waiting;
+    }
+
+    Ok(started.elapsed())
+}
+
+/// Restarts the enabled processes among `indexes` from scratch `runs` times.
+///
+/// Processes that weren't running before are stopped again at the end.
+pub fn bench(
+    state: &mut State,
+    indexes: &[usize],
+    config: &Config,
+    runs: usize,
+    timeout: Duration,
+    persist: &mut Persist,
+) -> Result<Report, Box<dyn std::error::Error>> {
+    state.update_all();
+    let indexes: Vec<usize> = indexes
+        .iter()
+        .copied()
+        .filter(|index| state[*index].is_enabled())
+        .collect();
+    let was_running: Vec<bool> = indexes
+        .iter()
+        .map(|index| state[*index].is_running())
+        .collect();
+
+    let mut timings: Vec<Timing> = indexes
+        .iter()
+        .map(|index| Timing {
+            name: state[*index].to_string(),
+            runs: vec![],
+        })
+        .collect();
+    let mut totals = vec![];
+
+    for _ in 0..runs {
+        for index in indexes.iter() {
+            state[*index].kill_for_restart(config)?;
+        }
+        totals.push(start_all(
+            state,
+            &indexes,
+            config,
+            timeout,
+            &mut timings,
+            persist,
+        )?);
+    }
+
+    for (index, was_running) in indexes.iter().zip(was_running) {
+        if !was_running {
+            state[*index].kill(config)?;
+        }
+    }
+
+    timings.sort_by_key(|timing| std::cmp::Reverse(timing.average()));
+    Ok(Report { timings, totals })
+}
@@ -0,0 +1,199 @@
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::error;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Settings shared by all processes, kept under `config` in the state file.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct Config {
+    /// Template for default log paths, e.g. `{log_dir}/{name}-{date}.log`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_name_template: Option<String>,
+    /// Size in bytes over which `watchman watch` rotates a log.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_log_size: Option<u64>,
+    /// Most processes allowed to run at once, `critical` ones can always start.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_running: Option<usize>,
+    /// Refuse to kill processes watchman didn't spawn itself. Set with `--safe`, never stored.
+    #[serde(skip)]
+    pub safe: bool,
+    /// Running as `watchman watch`, never stored. Output that isn't captured is discarded
+    /// instead of mixing into its own.
+    #[serde(skip)]
+    pub daemon: bool,
+    /// Most processes `watchman watch` restarts in one pass, the rest wait for the next one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_restarts_per_pass: Option<usize>,
+    /// Upper bound of a random delay between restarts in a pass, in milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_jitter: Option<u64>,
+    /// Signal processes without their own `stop_signal` are stopped with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_signal: Option<String>,
+    /// Seconds processes without their own `stop_timeout` get to exit before being killed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_timeout: Option<u64>,
+    /// Shell command setting up the environment, run before processes are started.
+    /// Nothing is started when it fails.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_flight: Option<String>,
+    /// Shell command run once a command has left no process running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_flight: Option<String>,
+    /// Unix socket `watchman watch` takes requests on, needs the `socket` feature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket_path: Option<PathBuf>,
+    /// Leave processes started by a command running once it exits, true by default.
+    /// The CLI otherwise stays attached and stops them on exit, same as `--foreground`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detach_on_exit: Option<bool>,
+    /// Pass the session id to every started process as `WATCHMAN_RUN_ID`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub inject_run_id: bool,
+    /// The session id when `inject_run_id` is set, filled in from the state file.
+    #[serde(skip)]
+    pub run_id: Option<String>,
+}
+
+impl Config {
+    pub fn detach_on_exit(&self) -> bool {
+        self.detach_on_exit.unwrap_or(true)
+    }
+}
+
+/// Log path of processes without an `output` when there's no `log_name_template`.
+pub const DEFAULT_LOG_NAME_TEMPLATE: &str = "{log_dir}/{name}.log";
+/// Rotated logs kept when `watchman watch` rotates one for its `max_log_size`.
+pub const MAX_LOG_SIZE_KEEP: usize = 5;
+
+/// Stop signal when neither the process nor the config sets one.
+pub const DEFAULT_STOP_SIGNAL: &str = "TERM";
+/// Stop timeout when neither the process nor the config sets one, in seconds.
+pub const DEFAULT_STOP_TIMEOUT: u64 = 10;
+
+/// A setting along with where it came from: "process", "config" or "default".
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolved<T> {
+    pub value: T,
+    pub source: &'static str,
+}
+
+impl<T> Resolved<T> {
+    /// Takes the process's own value over the config's over `default`.
+    pub fn resolve(process: Option<T>, config: Option<T>, default: T) -> Self {
+        match (process, config) {
+            (Some(value), _) => Resolved {
+                value,
+                source: "process",
+            },
+            (None, Some(value)) => Resolved {
+                value,
+                source: "config",
+            },
+            (None, None) => Resolved {
+                value: default,
+                source: "default",
+            },
+        }
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[derive(Debug)]
+pub struct TemplateError {
+    msg: String,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl error::Error for TemplateError {}
+
+/// Values available to `log_name_template`.
+pub struct LogNameVars<'a> {
+    pub log_dir: &'a str,
+    pub name: &'a str,
+    /// Of the process, `None` while the log is opened for it to be started. `{pid}` is then
+    /// a placeholder, the log is renamed once the process has a pid.
+    pub pid: Option<i32>,
+}
+
+impl LogNameVars<'_> {
+    fn get(&self, var: &str) -> Option<String> {
+        match var {
+            "log_dir" => Some(self.log_dir.to_string()),
+            "name" => Some(self.name.to_string()),
+            "date" => Some(Local::now().format("%Y-%m-%d").to_string()),
+            "pid" => Some(match self.pid {
+                Some(pid) => pid.to_string(),
+                None => format!("starting-{}", std::process::id()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub fn expand_template(template: &str, vars: &LogNameVars) -> Result<String, TemplateError> {
+    let mut res = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        res.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| TemplateError {
+            msg: format!("Unclosed \"{{\" in template {:?}", template),
+        })?;
+        let var = &rest[start + 1..start + end];
+        let value = vars.get(var).ok_or_else(|| TemplateError {
+            msg: format!("Unknown variable {{{}}} in template {:?}", var, template),
+        })?;
+        res.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    res.push_str(rest);
+
+    Result::Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pid: Option<i32>) -> LogNameVars<'static> {
+        LogNameVars {
+            log_dir: "/logs",
+            name: "web",
+            pid,
+        }
+    }
+
+    #[test]
+    fn expand_template_fills_in_variables() {
+        let expanded = expand_template("{log_dir}/{name}-{pid}.log", &vars(Some(42))).unwrap();
+        assert_eq!(expanded, "/logs/web-42.log");
+        assert_eq!(
+            expand_template("plain.log", &vars(None)).unwrap(),
+            "plain.log"
+        );
+    }
+
+    #[test]
+    fn expand_template_has_a_placeholder_before_the_pid_is_known() {
+        let expanded = expand_template("{name}-{pid}", &vars(None)).unwrap();
+        assert_eq!(expanded, format!("web-starting-{}", std::process::id()));
+    }
+
+    #[test]
+    fn expand_template_rejects_unknown_and_unclosed_variables() {
+        let err = expand_template("{log_dir}/{nope}.log", &vars(None)).unwrap_err();
+        assert!(err.to_string().contains("{nope}"), "{}", err);
+        assert!(expand_template("{log_dir/x.log", &vars(None)).is_err());
+    }
+}
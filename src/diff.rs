@@ -0,0 +1,234 @@
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::state::ProcessConfig;
+use crate::state::StateFile;
+use crate::utils;
+
+pub enum Change {
+    Added(String),
+    Removed(String),
+    Changed {
+        name: String,
+        field: String,
+        old: Value,
+        new: Value,
+    },
+    /// A field of the `config` section, kept apart from processes, which could be named
+    /// `config` too.
+    ConfigChanged {
+        field: String,
+        old: Value,
+        new: Value,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added(name) => write!(f, "+ {}", name),
+            Change::Removed(name) => write!(f, "- {}", name),
+            Change::Changed {
+                name,
+                field,
+                old,
+                new,
+            } => write!(f, "~ {}: {} {} -> {}", name, field, old, new),
+            Change::ConfigChanged { field, old, new } => {
+                write!(f, "~ [config] {} {} -> {}", field, old, new)
+            }
+        }
+    }
+}
+
+/// Fields watchman keeps up to date itself, they change without the config changing.
+const RUNTIME_FIELDS: &[&str] = &[
+    "status",
+    "restarts",
+    "retries",
+    "log_path",
+    "adopted",
+    "last_output",
+    "cmd_override",
+    "parent_pid",
+];
+
+/// `path` the way it's pinned down once the process is started, as it is if it can't be.
+fn normalize_path(path: &Value, resolve: fn(&Path) -> io::Result<PathBuf>) -> Value {
+    path.as_str()
+        .and_then(|path| resolve(Path::new(path)).ok())
+        .map_or_else(|| path.clone(), |path| Value::from(path.to_string_lossy()))
+}
+
+/// Fields of a process as stored, minus the runtime ones. `cwd` and `output` are compared
+/// in the canonical form they're rewritten to on start.
+fn config_fields(process: &ProcessConfig) -> Map<String, Value> {
+    match serde_json::to_value(process) {
+        Ok(Value::Object(mut fields)) => {
+            for field in RUNTIME_FIELDS {
+                fields.remove(*field);
+            }
+            if let Some(cwd) = fields.get_mut("cwd") {
+                *cwd = normalize_path(cwd, |path| fs::canonicalize(path));
+            }
+            if let Some(output) = fields.get_mut("output") {
+                *output = normalize_path(output, utils::canonicalize_file);
+            }
+            fields
+        }
+        _ => Map::new(),
+    }
+}
+
+/// Fields that differ, with their old and new values.
+fn diff_fields(old: &Map<String, Value>, new: &Map<String, Value>) -> Vec<(String, Value, Value)> {
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let old = old.get(key).cloned().unwrap_or(Value::Null);
+            let new = new.get(key).cloned().unwrap_or(Value::Null);
+            if old == new {
+                None
+            } else {
+                Some((key.clone(), old, new))
+            }
+        })
+        .collect()
+}
+
+/// Compares two state files by process name, ignoring what watchman keeps up to date.
+pub fn diff(old: &StateFile, new: &StateFile) -> Vec<Change> {
+    let mut changes = vec![];
+
+    if let (Ok(Value::Object(old_config)), Ok(Value::Object(new_config))) = (
+        serde_json::to_value(&old.config),
+        serde_json::to_value(&new.config),
+    ) {
+        changes.extend(
+            diff_fields(&old_config, &new_config)
+                .into_iter()
+                .map(|(field, old, new)| Change::ConfigChanged { field, old, new }),
+        );
+    }
+
+    let find = |file: &StateFile, name: &str| -> Option<ProcessConfig> {
+        file.processes
+            .iter()
+            .find(|process| process.to_string() == name)
+            .cloned()
+    };
+
+    for process in &old.processes {
+        let name = process.to_string();
+        match find(new, &name) {
+            Some(other) => changes.extend(
+                diff_fields(&config_fields(process), &config_fields(&other))
+                    .into_iter()
+                    .map(|(field, old, new)| Change::Changed {
+                        name: name.clone(),
+                        field,
+                        old,
+                        new,
+                    }),
+            ),
+            None => changes.push(Change::Removed(name)),
+        }
+    }
+    for process in &new.processes {
+        let name = process.to_string();
+        if find(old, &name).is_none() {
+            changes.push(Change::Added(name));
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use serde_json::json;
+
+    fn fields(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(fields) => fields,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn diff_fields_lists_changed_added_and_dropped_keys() {
+        let old = fields(json!({ "cmd": "a", "cwd": "/x", "same": 1 }));
+        let new = fields(json!({ "cmd": "b", "port": 80, "same": 1 }));
+
+        assert_eq!(
+            diff_fields(&old, &new),
+            vec![
+                ("cmd".to_string(), json!("a"), json!("b")),
+                ("cwd".to_string(), json!("/x"), Value::Null),
+                ("port".to_string(), Value::Null, json!(80)),
+            ]
+        );
+    }
+
+    #[test]
+    fn config_fields_leave_out_runtime_state_and_resolve_paths() {
+        let cwd = std::env::current_dir().unwrap();
+        let process = ProcessConfig {
+            restarts: 3,
+            retries: 2,
+            adopted: true,
+            parent_pid: Some(1),
+            log_path: Some("/logs/web.log".into()),
+            last_output: Some("hi".to_string()),
+            cwd: Some(".".into()),
+            output: Some("web.log".into()),
+            ..ProcessConfig::new("web".to_string(), None)
+        };
+
+        let fields = config_fields(&process);
+        for field in RUNTIME_FIELDS {
+            assert!(!fields.contains_key(*field), "{}", field);
+        }
+        assert_eq!(fields["cmd"], json!("web"));
+        assert_eq!(fields["cwd"], json!(cwd.to_string_lossy()));
+        assert_eq!(
+            fields["output"],
+            json!(cwd.join("web.log").to_string_lossy())
+        );
+    }
+
+    #[test]
+    fn diff_tells_config_apart_from_a_process_named_config() {
+        let file = |stop_timeout: u64, cmd: &str| StateFile {
+            config: Config {
+                stop_timeout: Some(stop_timeout),
+                ..Default::default()
+            },
+            processes: vec![ProcessConfig::new(
+                cmd.to_string(),
+                Some("config".to_string()),
+            )],
+            ..Default::default()
+        };
+
+        let changes: Vec<String> = diff(&file(3, "a"), &file(4, "b"))
+            .iter()
+            .map(Change::to_string)
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                "~ [config] stop_timeout 3 -> 4",
+                "~ config: cmd \"a\" -> \"b\""
+            ]
+        );
+    }
+}
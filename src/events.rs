@@ -0,0 +1,115 @@
+use chrono::Local;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::state::State;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Lines meant for people, same shape as `watchman run` output.
+    Human,
+    /// Newline-delimited JSON, one event per line.
+    Json,
+}
+
+#[derive(Debug)]
+pub struct LogFormatError {
+    input: String,
+}
+
+impl fmt::Display for LogFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown log format {:?}, expected \"human\" or \"json\"",
+            self.input
+        )
+    }
+}
+
+impl error::Error for LogFormatError {}
+
+impl FromStr for LogFormat {
+    type Err = LogFormatError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(LogFormatError {
+                input: input.to_string(),
+            }),
+        }
+    }
+}
+
+/// A single line of the event stream.
+///
+/// The JSON schema is stable: `timestamp` (RFC 3339), `event`, `process`
+/// (null for events not tied to one process) and `details` (an object).
+#[derive(Debug, Serialize)]
+struct Event<'a> {
+    timestamp: String,
+    event: &'a str,
+    process: Option<&'a str>,
+    details: Value,
+}
+
+pub struct Events {
+    format: LogFormat,
+}
+
+impl Events {
+    pub fn new(format: LogFormat) -> Self {
+        Events { format }
+    }
+
+    pub fn emit(&self, event: &str, process: Option<&str>, details: Value) {
+        let now = Local::now();
+        match self.format {
+            LogFormat::Json => {
+                let event = Event {
+                    timestamp: now.to_rfc3339(),
+                    event,
+                    process,
+                    details,
+                };
+                println!("{}", serde_json::to_string(&event).unwrap());
+            }
+            LogFormat::Human => {
+                let mut line = format!("{} SYS {}", now.format("%Y-%m-%d %H:%M:%S"), event);
+                if let Some(process) = process {
+                    line.push_str(&format!(" {:?}", process));
+                }
+                if let Value::Object(ref details) = details {
+                    for (key, value) in details {
+                        line.push_str(&format!(" {}={}", key, value));
+                    }
+                }
+                println!("{}", line);
+            }
+        }
+    }
+
+    /// Periodic status of every process. Only part of the JSON stream, people have `show`.
+    pub fn snapshot(&self, state: &State) {
+        if self.format != LogFormat::Json {
+            return;
+        }
+
+        let processes: Vec<Value> = state
+            .iter()
+            .map(|process| {
+                json!({
+                    "name": process.to_string(),
+                    "cmd": process.cmd,
+                    "status": process.status,
+                })
+            })
+            .collect();
+        self.emit("status", None, json!({ "processes": processes }));
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::utils::glob_match;
+
+/// Polls paths for changes, there's no need for anything fancier for a dev loop.
+pub struct FileWatcher {
+    paths: Vec<PathBuf>,
+    ignore: Vec<String>,
+    debounce: Duration,
+    snapshot: HashMap<PathBuf, SystemTime>,
+    pending_since: Option<Instant>,
+}
+
+impl FileWatcher {
+    pub fn new(paths: Vec<PathBuf>, ignore: Vec<String>, debounce: Duration) -> Self {
+        let mut watcher = FileWatcher {
+            paths,
+            ignore,
+            debounce,
+            snapshot: HashMap::new(),
+            pending_since: None,
+        };
+        watcher.snapshot = watcher.scan();
+        watcher
+    }
+
+    fn is_ignored(&self, path: &Path) -> bool {
+        let name = path
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().to_string());
+        let full = path.to_string_lossy();
+
+        self.ignore
+            .iter()
+            .any(|pattern| glob_match(pattern, &name) || glob_match(pattern, &full))
+    }
+
+    fn scan_into(&self, path: &Path, snapshot: &mut HashMap<PathBuf, SystemTime>) {
+        if self.is_ignored(path) {
+            return;
+        }
+
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return,
+        };
+        if metadata.is_dir() {
+            if let Ok(entries) = fs::read_dir(path) {
+                for entry in entries.filter_map(Result::ok) {
+                    self.scan_into(&entry.path(), snapshot);
+                }
+            }
+        } else if let Ok(modified) = metadata.modified() {
+            snapshot.insert(path.to_path_buf(), modified);
+        }
+    }
+
+    fn scan(&self) -> HashMap<PathBuf, SystemTime> {
+        let mut snapshot = HashMap::new();
+        self.paths
+            .iter()
+            .for_each(|path| self.scan_into(path, &mut snapshot));
+        snapshot
+    }
+
+    /// Whether it's time to restart: something changed and nothing has changed since for
+    /// the whole debounce window. Every new change starts the window over.
+    pub fn should_restart(&mut self) -> bool {
+        let snapshot = self.scan();
+        if snapshot != self.snapshot {
+            self.snapshot = snapshot;
+            self.pending_since = Some(Instant::now());
+            return false;
+        }
+
+        match self.pending_since {
+            Some(since) if since.elapsed() >= self.debounce => {
+                self.pending_since = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::system;
+
+/// How long a health check may take before it's considered failed.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs a health check command, healthy means it exited with 0 in time.
+pub fn check(cmd: &str) -> bool {
+    match system::run_shell_with_timeout(cmd, HEALTH_CHECK_TIMEOUT) {
+        Ok(Some(status)) => status.success(),
+        Ok(None) => {
+            eprintln!("Health check {:?} timed out", cmd);
+            false
+        }
+        Err(err) => {
+            eprintln!("Health check {:?} failed to run: {}", cmd, err);
+            false
+        }
+    }
+}
+
+/// How long a connection attempt to a port may take.
+const PORT_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Whether something accepts TCP connections on the local `port`, IPv4 or IPv6.
+pub fn is_listening(port: u16) -> bool {
+    let addrs = [
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+        SocketAddr::from((Ipv6Addr::LOCALHOST, port)),
+    ];
+    addrs
+        .iter()
+        .any(|addr| TcpStream::connect_timeout(addr, PORT_PROBE_TIMEOUT).is_ok())
+}
@@ -0,0 +1,374 @@
+use std::collections::BTreeMap;
+use std::error;
+use std::fs;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::state::MayError;
+use crate::state::Persist;
+use crate::state::ProcessConfig;
+use crate::state::State;
+use crate::state::StateError;
+use crate::state::StateTrait;
+
+/// Processes of a Procfile, a `name: command` per line. Commands are shell commands, run with
+/// `sh -c`.
+pub fn read_procfile(path: &Path) -> Result<Vec<ProcessConfig>, Box<dyn error::Error>> {
+    let contents = fs::read_to_string(path)?;
+    let mut processes = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.find(':') {
+            Some(at) if at > 0 && !line[at + 1..].trim().is_empty() => {
+                processes.push(ProcessConfig {
+                    args: vec!["-c".to_string(), line[at + 1..].trim().to_string()],
+                    ..ProcessConfig::new("sh".to_string(), Some(line[..at].trim().to_string()))
+                })
+            }
+            _ => {
+                return Err(Box::new(StateError::new(&format!(
+                    "{:?} line {}: expected \"name: command\", got {:?}",
+                    path,
+                    number + 1,
+                    line
+                ))))
+            }
+        }
+    }
+
+    Ok(processes)
+}
+
+/// A line of a compose file that isn't blank or a comment.
+struct Line<'a> {
+    number: usize,
+    indent: usize,
+    text: &'a str,
+}
+
+/// A `key: value` line with the deeper lines below it.
+type Entry<'a, 'b> = (&'a str, &'a str, &'b [Line<'a>]);
+
+/// Splits `lines` at the indent of the first one into `key: value` entries, each with the
+/// deeper lines below it.
+fn entries<'a, 'b>(path: &Path, lines: &'b [Line<'a>]) -> Result<Vec<Entry<'a, 'b>>, StateError> {
+    let indent = match lines.first() {
+        Some(line) => line.indent,
+        None => return Ok(vec![]),
+    };
+
+    let mut entries = vec![];
+    let mut index = 0;
+    while index < lines.len() {
+        let line = &lines[index];
+        let end = lines[index + 1..]
+            .iter()
+            .position(|next| next.indent <= indent)
+            .map_or(lines.len(), |at| index + 1 + at);
+        let (key, value) = match line.text.find(':') {
+            Some(at) if line.text[at + 1..].is_empty() || line.text[at + 1..].starts_with(' ') => {
+                (unquote(&line.text[..at]), line.text[at + 1..].trim())
+            }
+            _ => {
+                return Err(StateError::new(&format!(
+                    "{:?} line {}: expected \"key: value\", got {:?}",
+                    path, line.number, line.text
+                )))
+            }
+        };
+        entries.push((key, value, &lines[index + 1..end]));
+        index = end;
+    }
+
+    Ok(entries)
+}
+
+/// Items of a `- item` list.
+fn items<'a>(lines: &[Line<'a>]) -> Vec<&'a str> {
+    lines
+        .iter()
+        .filter_map(|line| line.text.strip_prefix('-'))
+        .map(|item| unquote(item.trim()))
+        .collect()
+}
+
+fn unquote(value: &str) -> &str {
+    let quoted = value.len() >= 2
+        && (value.starts_with('"') && value.ends_with('"')
+            || value.starts_with('\'') && value.ends_with('\''));
+    if quoted {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
+
+/// Words of a command, quotes keep spaces in a word.
+fn words(command: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    for c in command.chars() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// A compose `command`: a plain string is kept as is, one with quotes or a list is split
+/// into `cmd` and `args`.
+fn command(value: &str, list: &[Line]) -> Option<(String, Vec<String>)> {
+    let mut words: Vec<String> = if value.is_empty() {
+        items(list).into_iter().map(str::to_string).collect()
+    } else if value.starts_with('[') && value.ends_with(']') {
+        words(&value[1..value.len() - 1].replace(',', " "))
+    } else if !value.contains('"') && !value.contains('\'') {
+        return Some((value.to_string(), vec![]));
+    } else {
+        words(value)
+    };
+    if words.is_empty() {
+        return None;
+    }
+    let cmd = words.remove(0);
+    Some((cmd, words))
+}
+
+/// Services of a docker-compose file with a `command`, to be run on this host rather than
+/// in containers. Only the simple subset it takes is understood: block mappings, `command`
+/// as a string or list and `environment` as a list or mapping.
+pub fn read_compose(path: &Path) -> Result<Vec<ProcessConfig>, Box<dyn error::Error>> {
+    let contents = fs::read_to_string(path)?;
+    let lines: Vec<Line> = contents
+        .lines()
+        .enumerate()
+        .map(|(number, text)| Line {
+            number: number + 1,
+            indent: text.len() - text.trim_start().len(),
+            text: text.trim(),
+        })
+        .filter(|line| !line.text.is_empty() && !line.text.starts_with('#'))
+        .collect();
+
+    let services = entries(path, &lines)?
+        .into_iter()
+        .find(|(key, _, _)| *key == "services")
+        .map(|(_, _, services)| services)
+        .ok_or_else(|| StateError::new(&format!("{:?} has no services", path)))?;
+
+    let mut processes = vec![];
+    for (name, _, settings) in entries(path, services)? {
+        let settings = entries(path, settings)?;
+        let (cmd, args) = match settings
+            .iter()
+            .find(|(key, _, _)| *key == "command")
+            .and_then(|(_, value, list)| command(value, list))
+        {
+            Some(command) => command,
+            None => {
+                eprintln!("Skipping {}, it has no command", name);
+                continue;
+            }
+        };
+
+        let mut env = BTreeMap::new();
+        if let Some((_, _, vars)) = settings.iter().find(|(key, _, _)| *key == "environment") {
+            // An inline value like `[]` or `{}` has no lines below it, so no variables.
+            let vars: Vec<(&str, &str)> = match vars.first() {
+                None => vec![],
+                // Variables listed without a value are passed on from the host anyway.
+                Some(first) if first.text.starts_with('-') => items(vars)
+                    .into_iter()
+                    .filter_map(|item| item.find('=').map(|at| (&item[..at], &item[at + 1..])))
+                    .collect(),
+                Some(_) => entries(path, vars)?
+                    .into_iter()
+                    .map(|(key, value, _)| (key, unquote(value)))
+                    .collect(),
+            };
+            env.extend(
+                vars.into_iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string())),
+            );
+        }
+
+        processes.push(ProcessConfig {
+            args,
+            env,
+            ..ProcessConfig::new(cmd, Some(name.to_string()))
+        });
+    }
+
+    Ok(processes)
+}
+
+/// Whether `path` looks like a docker-compose file rather than a Procfile.
+fn is_compose(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "yml" || extension == "yaml")
+}
+
+/// Adds and starts the processes of a Procfile or docker-compose file, started in the
+/// directory of the file. Ones named like an existing process are skipped.
+pub fn run(state: &mut State, path: &Path, config: &Config, persist: &mut Persist) -> MayError {
+    let processes = if is_compose(path) {
+        read_compose(path)?
+    } else {
+        read_procfile(path)?
+    };
+    let dir = fs::canonicalize(path)?.parent().map(Path::to_path_buf);
+
+    let mut failed = 0;
+    for process in processes {
+        let name = process.to_string();
+        if state.iter().any(|existing| existing.matches(&name)) {
+            println!("Skipping {}, it exists already", name);
+            continue;
+        }
+        let process = ProcessConfig {
+            cwd: dir.clone(),
+            ..process
+        };
+        match state.add(process, config, None, persist) {
+            Ok(()) => println!("Imported {}", name),
+            Err(err) => {
+                eprintln!("Importing {} FAILED with {}", name, err);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(Box::new(StateError::new(&format!(
+            "{} processes of {:?} failed to start",
+            failed, path
+        ))));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::ScratchDir;
+
+    #[test]
+    fn words_keep_quoted_spaces() {
+        assert_eq!(words("a  b"), vec!["a", "b"]);
+        assert_eq!(words("sh -c 'echo hi'"), vec!["sh", "-c", "echo hi"]);
+        assert_eq!(words("a \"\" b"), vec!["a", "", "b"]);
+        assert!(words("  ").is_empty());
+    }
+
+    #[test]
+    fn command_splits_only_when_it_has_to() {
+        assert_eq!(
+            command("sleep 10", &[]),
+            Some(("sleep 10".to_string(), vec![]))
+        );
+        assert_eq!(
+            command("[\"sleep\", \"10\"]", &[]),
+            Some(("sleep".to_string(), vec!["10".to_string()]))
+        );
+        assert_eq!(
+            command("sh -c 'echo hi'", &[]),
+            Some((
+                "sh".to_string(),
+                vec!["-c".to_string(), "echo hi".to_string()]
+            ))
+        );
+        assert_eq!(command("[]", &[]), None);
+    }
+
+    #[test]
+    fn read_procfile_runs_commands_through_sh() {
+        let dir = ScratchDir::new("procfile");
+        let path = dir.path().join("Procfile");
+        fs::write(&path, "# comment\nweb: cd app && PORT=$PORT npm start\n").unwrap();
+
+        let processes = read_procfile(&path).unwrap();
+        assert_eq!(processes.len(), 1);
+        assert_eq!(processes[0].name.as_deref(), Some("web"));
+        assert_eq!(processes[0].cmd, "sh");
+        assert_eq!(
+            processes[0].args,
+            vec!["-c", "cd app && PORT=$PORT npm start"]
+        );
+        assert_eq!(
+            format!("{:+}", processes[0]),
+            "web [sh -c cd app && PORT=$PORT npm start]"
+        );
+
+        fs::write(&path, "web:\n").unwrap();
+        assert!(read_procfile(&path).is_err());
+    }
+
+    #[test]
+    fn read_compose_takes_services_with_a_command() {
+        let dir = ScratchDir::new("compose");
+        let path = dir.path().join("compose.yml");
+        fs::write(
+            &path,
+            "version: \"3\"
+services:
+  api:
+    image: api
+    command:
+      - serve
+      - --port=80
+    environment:
+      - A=1
+      - FROM_HOST
+  worker:
+    command: work --fast
+    environment:
+      B: \"two\"
+  db:
+    image: postgres
+  empty:
+    command: idle
+    environment: []
+  blank:
+    command: idle
+    environment:
+",
+        )
+        .unwrap();
+
+        let processes = read_compose(&path).unwrap();
+        let names: Vec<&str> = processes.iter().filter_map(|p| p.name.as_deref()).collect();
+        assert_eq!(names, vec!["api", "worker", "empty", "blank"]);
+
+        assert_eq!(processes[0].cmd, "serve");
+        assert_eq!(processes[0].args, vec!["--port=80"]);
+        assert_eq!(processes[0].env.get("A").map(String::as_str), Some("1"));
+        assert!(!processes[0].env.contains_key("FROM_HOST"));
+        assert_eq!(processes[1].cmd, "work --fast");
+        assert_eq!(processes[1].env.get("B").map(String::as_str), Some("two"));
+        assert!(processes[2].env.is_empty());
+        assert!(processes[3].env.is_empty());
+    }
+
+    #[test]
+    fn read_compose_needs_services() {
+        let dir = ScratchDir::new("compose-bad");
+        let path = dir.path().join("compose.yml");
+        fs::write(&path, "version: \"3\"\n").unwrap();
+        assert!(read_compose(&path).is_err());
+
+        fs::write(&path, "services:\n  api\n").unwrap();
+        assert!(read_compose(&path).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::state::MayError;
+use crate::state::ProcessConfig;
+use crate::state::StateError;
+use crate::utils;
+
+/// How often `--follow` checks the logs for new output.
+const FOLLOW_POLL: Duration = Duration::from_millis(250);
+
+/// The log `process` writes to: where output went when it was last started, otherwise its
+/// `output`.
+pub fn log_of(process: &ProcessConfig) -> Result<PathBuf, StateError> {
+    process
+        .log_path
+        .clone()
+        .or_else(|| process.output.clone().filter(|_| process.capture_output))
+        .ok_or_else(|| {
+            StateError::new(&format!(
+                "{} has no log, it hasn't been started with its output captured",
+                process
+            ))
+        })
+}
+
+/// Prints the last `lines` lines of every log, headed by its path when there are several.
+/// With `follow` it keeps printing whatever is added to them, until interrupted.
+pub fn print(paths: &[PathBuf], lines: usize, follow: bool) -> MayError {
+    let headed = paths.len() > 1;
+    for path in paths {
+        if headed {
+            println!("==> {} <==", path.display());
+        }
+        if let Some(tail) = utils::read_tail(path, lines) {
+            println!("{}", tail);
+        }
+    }
+    if !follow {
+        return Result::Ok(());
+    }
+
+    let mut offsets: Vec<u64> = paths.iter().map(|path| file_len(path)).collect();
+    let mut last = None;
+    loop {
+        for (index, path) in paths.iter().enumerate() {
+            let len = file_len(path);
+            // Emptied by a rotation, start over from the top.
+            if len < offsets[index] {
+                offsets[index] = 0;
+            }
+            if len == offsets[index] {
+                continue;
+            }
+
+            if headed && last != Some(index) {
+                println!("\n==> {} <==", path.display());
+                last = Some(index);
+            }
+            offsets[index] += copy_from(path, offsets[index])?;
+        }
+        thread::sleep(FOLLOW_POLL);
+    }
+}
+
+fn file_len(path: &Path) -> u64 {
+    path.metadata().map_or(0, |metadata| metadata.len())
+}
+
+/// Writes everything in `path` past `offset` to stdout, returns how many bytes that was.
+fn copy_from(path: &Path, offset: u64) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buffer = vec![];
+    file.read_to_end(&mut buffer)?;
+
+    let mut stdout = io::stdout();
+    stdout.write_all(&buffer)?;
+    stdout.flush()?;
+    Result::Ok(buffer.len() as u64)
+}
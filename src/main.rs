@@ -1,25 +1,52 @@
 use colored::*;
-use dialoguer::{theme::ColorfulTheme, MultiSelect};
+use dialoguer::{theme::ColorfulTheme, Confirm, MultiSelect};
 use std::error;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use structopt::StructOpt;
 
+use crate::config::Config;
+use crate::events::{Events, LogFormat};
+use crate::selection::Selection;
+use crate::state::MayError;
+use crate::state::Persist;
 use crate::state::ProcessConfig;
 use crate::state::ProcessStatus;
+use crate::state::RestartPolicy;
+use crate::state::RunOutcome;
 use crate::state::State;
+use crate::state::StateError;
+use crate::state::StateFile;
 use crate::state::StateTrait;
+use crate::system::StopOutcome;
 use crate::utils::get_state_path;
 
+mod bench;
+mod config;
+mod diff;
+mod events;
+mod filewatch;
+mod health;
+mod import;
+mod logs;
+mod paths;
+mod selection;
+mod snapshot;
+#[cfg(feature = "socket")]
+mod socket;
 mod state;
+mod supervise;
 mod system;
+mod task;
 mod utils;
+mod version;
+mod wizard;
 
 // TODO: set cmd
-// TODO: enable command by name
-// TODO: disable command by name
-// TODO: remove command
 #[derive(Debug, StructOpt)]
+// Parsed once per run, the size of "add" doesn't matter.
+#[allow(clippy::large_enum_variant)]
 enum SubCommand {
     #[structopt(name = "add")]
     /// Adds a command
@@ -30,6 +57,102 @@ enum SubCommand {
         command: String,
         #[structopt(long = "name")]
         name: Option<String>,
+        /// Directory to start the process in
+        #[structopt(long = "cwd", parse(from_os_str))]
+        cwd: Option<PathBuf>,
+        /// Argument passed after the command as is, spaces included, can be repeated
+        #[structopt(
+            long = "arg",
+            raw(number_of_values = "1", allow_hyphen_values = "true")
+        )]
+        args: Vec<String>,
+        /// Env var for the process as KEY=VALUE, can be repeated
+        #[structopt(
+            long = "env",
+            parse(try_from_str = "utils::parse_env_var"),
+            raw(number_of_values = "1")
+        )]
+        env: Vec<(String, String)>,
+        /// File with KEY=VALUE lines passed to the process as env vars
+        #[structopt(long = "env-file", parse(from_os_str))]
+        env_file: Option<PathBuf>,
+        /// Signal making the process re-read its env file, used by "reload-env"
+        #[structopt(long = "reload-signal")]
+        reload_signal: Option<String>,
+        /// Launches the command under a prefix, e.g. --wrapper "strace -f"
+        #[structopt(long = "wrapper")]
+        wrapper: Option<String>,
+        /// Shell command that exits with 0 when the process is healthy
+        #[structopt(long = "health-check")]
+        health_check: Option<String>,
+        /// Shell command that exits with 0 once the process is ready, gates its dependents
+        #[structopt(long = "readiness-check")]
+        readiness_check: Option<String>,
+        /// Shell command `watch` runs every pass, the process is restarted when it fails
+        #[structopt(long = "liveness-check")]
+        liveness_check: Option<String>,
+        /// Local TCP port the process listens on
+        #[structopt(long = "port")]
+        port: Option<u16>,
+        /// Only considers the process ready and live while something listens on --port
+        #[structopt(long = "verify-port")]
+        verify_port: bool,
+        /// Runs the health check before adopting an already running process
+        #[structopt(long = "verify-adopted")]
+        verify_adopted: bool,
+        /// Shell command run before "watch" restarts the process, gets the exit code and restart count
+        #[structopt(long = "on-restart")]
+        on_restart: Option<String>,
+        /// When "watch" restarts the process: always, on-failure or never
+        #[structopt(long = "restart", default_value = "always")]
+        restart: RestartPolicy,
+        /// Restarts in a row "watch" makes before giving up on the process
+        #[structopt(long = "max-retries")]
+        max_retries: Option<u32>,
+        /// Seconds before restarting a process that died again, doubled with every retry
+        #[structopt(long = "restart-backoff")]
+        restart_backoff: Option<u64>,
+        /// Stops the process once this pid is gone, checked by "watch"
+        #[structopt(long = "parent-pid")]
+        parent_pid: Option<i32>,
+        /// Exempts the process from the max_running limit
+        #[structopt(long = "critical")]
+        critical: bool,
+        /// Restarted before processes with a lower priority when several are down at once
+        #[structopt(long = "priority", default_value = "0")]
+        priority: u32,
+        /// Lets the process write to watchman's own output instead of a log file
+        #[structopt(long = "no-capture")]
+        no_capture: bool,
+        /// Signal to stop the process with, e.g. TERM, before killing it after the stop timeout
+        #[structopt(long = "stop-signal")]
+        stop_signal: Option<String>,
+        /// Seconds the process gets to exit after the stop signal
+        #[structopt(long = "stop-timeout")]
+        stop_timeout: Option<u64>,
+        /// Waits for the readiness check to pass, removing the process again if it doesn't
+        #[structopt(long = "wait")]
+        wait: bool,
+        /// Seconds to wait for with --wait
+        #[structopt(long = "wait-timeout", default_value = "30")]
+        wait_timeout: u64,
+        /// Name of a process this one needs, can be repeated
+        #[structopt(long = "depends-on")]
+        depends_on: Vec<String>,
+        /// Restarts the process whenever one of its dependencies is restarted
+        #[structopt(long = "restart-on-dependency-restart")]
+        restart_dependents_on_dependency_restart: bool,
+    },
+    #[structopt(name = "remove")]
+    /// Stops and removes every process matching a name (or cmd for unnamed ones)
+    Remove {
+        target: String,
+        /// Also deletes the log files of removed processes
+        #[structopt(long = "purge-logs")]
+        purge_logs: bool,
+        /// Deletes log files without asking
+        #[structopt(long = "force")]
+        force: bool,
     },
     #[structopt(name = "run")]
     /// Runs a command handling restarts and logs
@@ -40,34 +163,297 @@ enum SubCommand {
         command: Vec<String>,
         #[structopt(long = "log")]
         output: Option<String>,
+        /// Adds a random delay of up to this many milliseconds to every restart
+        #[structopt(long = "restart-jitter")]
+        restart_jitter: Option<u64>,
+        /// Restarts the command when files under this path change, can be repeated
+        #[structopt(long = "watch", parse(from_os_str))]
+        watch: Vec<PathBuf>,
+        /// Milliseconds file changes have to settle for before restarting
+        #[structopt(long = "watch-interval", default_value = "500")]
+        watch_interval: u64,
+        /// Pattern of files not to watch, e.g. '*.tmp', can be repeated
+        #[structopt(long = "ignore")]
+        ignore: Vec<String>,
+        /// Output lines buffered while the log file is being written, at least 1
+        #[structopt(
+            long = "log-buffer",
+            default_value = "10000",
+            parse(try_from_str = "utils::parse_log_buffer")
+        )]
+        log_buffer: usize,
+        /// What happens when the buffer is full: "drop" lines or "block" the command
+        #[structopt(long = "on-full", default_value = "drop")]
+        overflow: system::OverflowPolicy,
+        /// Prefixes output lines with the "pid" or the "generation" of the instance writing them
+        #[structopt(long = "tag")]
+        tag: Option<system::LineTag>,
+    },
+    #[structopt(name = "version")]
+    /// Shows version and build info
+    Version {
+        /// Prints machine-readable JSON, including the state file format and features
+        #[structopt(long = "json")]
+        json: bool,
     },
     #[structopt(name = "config")]
     /// Shows configuration file location
-    Config,
-    #[structopt(name = "fix")]
+    Config {
+        #[structopt(subcommand)]
+        cmd: Option<ConfigCommand>,
+    },
+    #[structopt(name = "diff-config")]
+    /// Lists processes added, removed or changed between two state files, ignoring statuses
+    ///
+    /// Example:
+    /// watchman diff-config old.json new.json
+    DiffConfig {
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+    },
+    #[structopt(name = "fix", raw(alias = "\"start-all\""))]
     /// Ensures that all configured processes are running
-    Fix,
+    Fix {
+        #[structopt(flatten)]
+        selection: Selection,
+    },
+    #[structopt(name = "bench")]
+    /// Starts enabled processes from scratch, timing how long each takes to get ready
+    Bench {
+        /// How many times to start everything, timings are averaged
+        #[structopt(long = "runs", default_value = "1")]
+        runs: usize,
+        /// Seconds a process may take to pass its readiness check
+        #[structopt(long = "timeout", default_value = "30")]
+        timeout: u64,
+        #[structopt(flatten)]
+        selection: Selection,
+    },
+    #[structopt(name = "restart-all")]
+    /// Restarts enabled processes, running or not
+    RestartAll {
+        #[structopt(flatten)]
+        selection: Selection,
+    },
+    #[structopt(name = "watch", raw(alias = "\"daemon\""))]
+    /// Keeps enabled processes running, checking on them periodically
+    Watch {
+        /// Seconds between checks
+        #[structopt(long = "interval", default_value = "5")]
+        interval: u64,
+        /// "human" or "json" for newline-delimited JSON events
+        #[structopt(long = "log-format", default_value = "human")]
+        log_format: LogFormat,
+        /// Does a single pass and exits, non-zero if an enabled process is still down
+        #[structopt(long = "once")]
+        once: bool,
+    },
     #[structopt(name = "show")]
     /// Updates and displays the state of all processes
-    Show,
+    Show {
+        /// Prints the updated processes as JSON, same as in the state file
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    #[structopt(name = "solo")]
+    /// Disables every process except the given one, remembering what was enabled
+    Solo { target: String },
+    #[structopt(name = "unsolo")]
+    /// Restores the processes that were enabled before "solo"
+    Unsolo,
+    #[structopt(name = "start", raw(alias = "\"enable\""))]
+    /// Starts the matching processes, also "enable"
+    Start {
+        /// Name, cmd for unnamed processes, or position in "show" counting from 1
+        target: Option<String>,
+        /// Starts every process
+        #[structopt(long = "all")]
+        all: bool,
+        #[structopt(flatten)]
+        selection: Selection,
+        /// Runs this command instead of the stored one, until the next restart
+        #[structopt(long = "override-cmd")]
+        override_cmd: Option<String>,
+    },
+    #[structopt(name = "stop", raw(alias = "\"disable\""))]
+    /// Stops and disables the matching processes, also "disable"
+    Stop {
+        /// Name, cmd for unnamed processes, or position in "show" counting from 1
+        target: Option<String>,
+        /// Stops every process
+        #[structopt(long = "all")]
+        all: bool,
+        #[structopt(flatten)]
+        selection: Selection,
+    },
+    #[structopt(name = "restart")]
+    /// Stops the matching processes and starts them again
+    Restart {
+        /// Name, cmd for unnamed processes, or position in "show" counting from 1
+        target: Option<String>,
+        /// Restarts every process
+        #[structopt(long = "all")]
+        all: bool,
+        #[structopt(flatten)]
+        selection: Selection,
+    },
+    #[structopt(name = "inspect")]
+    /// Shows everything known about the matching processes
+    Inspect { target: String },
+    #[structopt(name = "snapshot")]
+    /// Writes statuses, pids, resource usage and log tails of all processes to a file
+    Snapshot {
+        /// Defaults to watchman-snapshot-<date>-<time>.json in the current directory
+        #[structopt(parse(from_os_str))]
+        path: Option<PathBuf>,
+    },
+    #[structopt(name = "reap")]
+    /// Kills processes running a configured command that watchman doesn't track, e.g. after a crash
+    Reap {
+        /// Only lists what would be killed
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+        /// Kills without asking
+        #[structopt(long = "force")]
+        force: bool,
+    },
+    #[structopt(name = "import")]
+    /// Adds and starts the processes of a Procfile or the commands of a docker-compose file
+    Import {
+        /// Read as docker-compose when it ends with .yml or .yaml
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    #[structopt(name = "logs")]
+    /// Prints the end of the matching processes' logs
+    Logs {
+        target: String,
+        /// Lines to print from the end of each log
+        #[structopt(long = "lines", short = "n", default_value = "20")]
+        lines: usize,
+        /// Keeps printing output as it's written, until interrupted
+        #[structopt(long = "follow", short = "f")]
+        follow: bool,
+    },
+    #[structopt(name = "rotate-logs")]
+    /// Moves the logs of the matching (or all) processes to .1, starting them over
+    RotateLogs {
+        target: Option<String>,
+        /// Rotated logs to keep per log file
+        #[structopt(long = "keep", default_value = "5")]
+        keep: usize,
+        /// Signal the running processes afterwards, e.g. HUP for ones reopening their logs on it
+        #[structopt(long = "signal")]
+        signal: Option<String>,
+    },
+    #[structopt(name = "reload-env")]
+    /// Makes matching processes pick up env_file changes, signalling or else restarting them
+    ReloadEnv { target: String },
+    #[structopt(name = "task")]
+    /// Runs the commands of a task from the state file in order, lists tasks without a name
+    Task {
+        name: Option<String>,
+        /// Runs the remaining commands after one fails
+        #[structopt(long = "keep-going")]
+        keep_going: bool,
+    },
+    #[structopt(name = "usage")]
+    /// Shows memory and CPU of every running process including its children, biggest first
+    Usage,
+    #[structopt(name = "check-paths")]
+    /// Checks that the files and directories processes use can be written, listing every problem
+    CheckPaths,
+    #[structopt(name = "run-id")]
+    /// Prints the id of the current supervisor session
+    RunId,
+    #[structopt(name = "upgrade")]
+    /// Makes a running "watch" re-exec its binary, processes keep running
+    Upgrade,
+    #[structopt(name = "repair")]
+    /// Rewrites recorded statuses to match reality without starting or killing anything
+    Repair,
 }
 
+#[derive(Debug, StructOpt)]
+enum ConfigCommand {
+    #[structopt(name = "effective")]
+    /// Shows the settings every process ends up with and where each comes from
+    Effective,
+}
+
+impl SubCommand {
+    /// Read-only commands never write the state file, so they work on files we can't write.
+    fn is_read_only(&self) -> bool {
+        match self {
+            SubCommand::Run { .. }
+            | SubCommand::Config { .. }
+            | SubCommand::Version { .. }
+            | SubCommand::DiffConfig { .. }
+            | SubCommand::Snapshot { .. }
+            | SubCommand::Inspect { .. }
+            | SubCommand::Logs { .. }
+            | SubCommand::RotateLogs { .. }
+            | SubCommand::Upgrade
+            | SubCommand::CheckPaths
+            | SubCommand::Usage
+            | SubCommand::Task { .. }
+            | SubCommand::RunId
+            | SubCommand::Show { .. } => true,
+            SubCommand::Add { .. }
+            | SubCommand::Import { .. }
+            | SubCommand::Remove { .. }
+            | SubCommand::Fix { .. }
+            | SubCommand::RestartAll { .. }
+            | SubCommand::Bench { .. }
+            | SubCommand::Reap { .. }
+            | SubCommand::Solo { .. }
+            | SubCommand::Unsolo
+            | SubCommand::Start { .. }
+            | SubCommand::Stop { .. }
+            | SubCommand::Restart { .. }
+            | SubCommand::Watch { .. }
+            | SubCommand::ReloadEnv { .. }
+            | SubCommand::Repair => false,
+        }
+    }
+}
+
+/// State file used when there's no `--state`.
+const STATE_VAR: &str = "WATCHMAN_STATE";
+
 #[derive(Debug, StructOpt)]
 #[structopt()]
 struct Cli {
-    #[structopt(long = "state", short = "s", parse(from_os_str))]
-    state_path: Option<PathBuf>,
+    /// State file, can be repeated to layer processes of later files over the first one,
+    /// which is the one written to. Defaults to $WATCHMAN_STATE. Also "--file"
+    #[structopt(
+        long = "state",
+        short = "s",
+        parse(from_os_str),
+        raw(number_of_values = "1", alias = "\"file\"")
+    )]
+    state_paths: Vec<PathBuf>,
+    /// Never kills processes watchman didn't start itself
+    #[structopt(long = "safe")]
+    safe: bool,
+    /// Stays attached to the processes a command starts, stopping them on exit
+    #[structopt(long = "foreground")]
+    foreground: bool,
+    /// Creates an empty state file on the first run instead of asking how to set it up
+    #[structopt(long = "no-wizard")]
+    no_wizard: bool,
     #[structopt(subcommand)]
     cmd: Option<SubCommand>,
 }
 
-fn update_from_user(mut state: State) -> State {
-    let mut all_processes: Vec<&mut ProcessConfig> = state.iter_mut().collect();
-    let defs: Vec<bool> = all_processes.iter().map(|proc| proc.is_enabled()).collect();
+fn update_from_user(mut state: State, config: &Config, persist: &mut Persist) -> State {
+    let defs: Vec<bool> = state.iter().map(|proc| proc.is_enabled()).collect();
 
     let selections = MultiSelect::with_theme(&ColorfulTheme::default())
         .with_prompt("Pick processes you want to be running")
-        .items(&all_processes[..])
+        .items(&state[..])
         .defaults(&defs[..])
         .interact()
         .unwrap();
@@ -76,16 +462,18 @@ fn update_from_user(mut state: State) -> State {
         let new = selections.contains(&i);
         match (*old, new) {
             (true, false) => {
-                println!("Disabling {}", all_processes[i]);
-                all_processes[i].kill();
+                println!("Disabling {}", state[i]);
+                if let Err(err) = state[i].kill(config) {
+                    println!("Disabling {} FAILED with {}", state[i], err);
+                }
             }
             (false, true) => {
-                match all_processes[i].run() {
+                match state.run_one(i, config, persist) {
                     Result::Err(err) => {
-                        println!("Enabling {} FAILED with {}", all_processes[i], err);
+                        println!("Enabling {} FAILED with {}", state[i], err);
                     }
-                    _ => {
-                        println!("Enabling {}", all_processes[i]);
+                    Result::Ok(outcome) => {
+                        println!("Enabling {}, {}", state[i], outcome);
                     }
                 };
             }
@@ -96,18 +484,243 @@ fn update_from_user(mut state: State) -> State {
     state
 }
 
-fn interactive(mut state: State) -> Result<State, Box<dyn error::Error>> {
+fn interactive(
+    mut state: State,
+    config: &Config,
+    persist: &mut Persist,
+) -> Result<State, Box<dyn error::Error>> {
     if state.is_empty() {
         println!("No processes configured. See \"watchman --help\"");
         return Result::Ok(state);
     }
-    state.fix_all()?;
+    supervise::pre_flight(config)?;
+    state.fix_all(config, persist)?;
 
-    state = update_from_user(state);
+    state = update_from_user(state, config, persist);
 
     Result::Ok(state)
 }
 
+/// Processes a `start`, `stop` or `restart` applies to: the ones matching `target`, or with
+/// `--all` the ones `selection` picks.
+fn targets(
+    state: &State,
+    target: Option<String>,
+    all: bool,
+    selection: &Selection,
+) -> Result<Vec<usize>, StateError> {
+    match (target, all) {
+        (Some(_), false) if !selection.is_empty() => {
+            Result::Err(StateError::new("--only and --except go with --all"))
+        }
+        (Some(target), false) => state.matching(&target),
+        (None, true) => Result::Ok(selection.indexes(state)),
+        (Some(_), true) => Result::Err(StateError::new("Give either a process or --all")),
+        (None, false) => Result::Err(StateError::new("Give a process, or --all for all of them")),
+    }
+}
+
+/// Lists the processes that were adopted or started, leaving out the ones that just kept running.
+fn report_outcomes(report: &[(String, RunOutcome)]) {
+    for (name, outcome) in report {
+        match outcome {
+            RunOutcome::Adopted(pid) => println!("adopted: {} (pid {})", name, pid),
+            RunOutcome::Started(pid) => println!("started: {} (pid {})", name, pid),
+            RunOutcome::AlreadyRunning(_) => {}
+        }
+    }
+}
+
+fn print_version(json: bool) -> MayError {
+    let info = version::info();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+    } else {
+        match info.commit {
+            Some(commit) => println!("watchman {} ({})", info.version, commit),
+            None => println!("watchman {}", info.version),
+        }
+        println!("state format {}", info.state_format);
+    }
+
+    Result::Ok(())
+}
+
+fn diff_config(old: &Path, new: &Path) -> MayError {
+    let changes = diff::diff(&StateFile::from_file(old)?, &StateFile::from_file(new)?);
+    if changes.is_empty() {
+        println!("No differences");
+    }
+    for change in changes {
+        let line = change.to_string();
+        match change {
+            diff::Change::Added(_) => println!("{}", line.green()),
+            diff::Change::Removed(_) => println!("{}", line.red()),
+            diff::Change::Changed { .. } | diff::Change::ConfigChanged { .. } => {
+                println!("{}", line.yellow())
+            }
+        }
+    }
+
+    Result::Ok(())
+}
+
+fn inspect(state: &State, target: &str) -> MayError {
+    let matching: Vec<&ProcessConfig> = state.iter().filter(|proc| proc.matches(target)).collect();
+    if matching.is_empty() {
+        return Result::Err(Box::new(StateError::new(&format!(
+            "No process matches {:?}",
+            target
+        ))));
+    }
+
+    for proc in matching {
+        println!("{:+}", proc);
+        let mut fields = serde_json::to_value(proc)?;
+        // Printed as is below, escaped JSON is hard to read.
+        if let Some(fields) = fields.as_object_mut() {
+            fields.remove("last_output");
+        }
+        println!("{}", serde_json::to_string_pretty(&fields)?);
+        if let Some(ref output) = proc.last_output {
+            println!("last output:\n{}", output);
+        }
+    }
+
+    Result::Ok(())
+}
+
+fn reload_env(state: &mut State, target: &str, config: &Config) -> MayError {
+    let matching: Vec<&mut ProcessConfig> = state
+        .iter_mut()
+        .filter(|proc| proc.matches(target))
+        .collect();
+    if matching.is_empty() {
+        return Result::Err(Box::new(StateError::new(&format!(
+            "No process matches {:?}",
+            target
+        ))));
+    }
+
+    for proc in matching {
+        let restarted = proc.reload_env(config)?;
+        if restarted {
+            eprintln!("{} has no reload_signal, restarted it instead", proc);
+        } else if proc.is_running() {
+            println!("Signalled {} to reload its env", proc);
+        } else {
+            println!("{} isn't running, it gets the env when started", proc);
+        }
+    }
+
+    Result::Ok(())
+}
+
+/// How long CPU usage is measured for by "usage".
+const USAGE_SAMPLE: Duration = Duration::from_millis(500);
+
+fn show_usage(state: &State) {
+    let running: Vec<&ProcessConfig> = state.iter().filter(|proc| proc.is_running()).collect();
+    if running.is_empty() {
+        println!("No processes running");
+        return;
+    }
+
+    let pids: Vec<i32> = running.iter().filter_map(|proc| proc.get_pid()).collect();
+    let mut rows: Vec<(String, i32, system::TreeUsage)> = running
+        .iter()
+        .zip(pids.iter())
+        .zip(system::get_tree_usage(&pids, USAGE_SAMPLE))
+        .filter_map(|((proc, pid), usage)| Some((proc.to_string(), *pid, usage?)))
+        .collect();
+    rows.sort_by_key(|(_, _, usage)| std::cmp::Reverse(usage.memory_kb));
+
+    let width = rows
+        .iter()
+        .map(|(name, _, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max("PROCESS".len());
+    println!(
+        "{:width$}  {:>7}  {:>5}  {:>10}  {:>6}",
+        "PROCESS",
+        "PID",
+        "PROCS",
+        "MEMORY",
+        "CPU",
+        width = width
+    );
+    let mut total = system::TreeUsage::default();
+    for (name, pid, usage) in &rows {
+        println!(
+            "{:width$}  {:>7}  {:>5}  {:>7.1} MB  {:>5.1}%",
+            name,
+            pid,
+            usage.processes,
+            usage.memory_kb as f64 / 1024.0,
+            usage.cpu_percent,
+            width = width
+        );
+        total.memory_kb += usage.memory_kb;
+        total.cpu_percent += usage.cpu_percent;
+        total.processes += usage.processes;
+    }
+    println!(
+        "{:width$}  {:>7}  {:>5}  {:>7.1} MB  {:>5.1}%",
+        "total",
+        "",
+        total.processes,
+        total.memory_kb as f64 / 1024.0,
+        total.cpu_percent,
+        width = width
+    );
+}
+
+fn show_bench(report: &bench::Report) {
+    let width = report
+        .timings
+        .iter()
+        .map(|timing| timing.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("PROCESS".len());
+    println!(
+        "{:width$}  {:>8}  {:>8}  {:>8}",
+        "PROCESS",
+        "AVG",
+        "MIN",
+        "MAX",
+        width = width
+    );
+    for timing in &report.timings {
+        println!(
+            "{:width$}  {:>7.2}s  {:>7.2}s  {:>7.2}s",
+            timing.name,
+            timing.average().as_secs_f64(),
+            timing.min().as_secs_f64(),
+            timing.max().as_secs_f64(),
+            width = width
+        );
+    }
+    println!(
+        "{:width$}  {:>7.2}s  ({} runs)",
+        "total",
+        report.average_total().as_secs_f64(),
+        report.totals.len(),
+        width = width
+    );
+}
+
+fn show_effective(state: &State, config: &Config) {
+    for proc in state {
+        let signal = proc.stop_signal(config);
+        let timeout = proc.stop_timeout(config);
+        println!("{:+}", proc);
+        println!("  stop_signal  {} ({})", signal.value, signal.source);
+        println!("  stop_timeout {}s ({})", timeout.value, timeout.source);
+    }
+}
+
 fn show(state: &State) {
     if state.is_empty() {
         return println!("No processes configured. See \"watchman --help\"");
@@ -116,54 +729,537 @@ fn show(state: &State) {
     state.iter().for_each(|proc| {
         let status_symbol = match proc.status {
             ProcessStatus::Disabled => " ".normal(),
-            ProcessStatus::Running(_) => "✔".green().bold(),
+            ProcessStatus::Running(..) => "✔".green().bold(),
             ProcessStatus::Invalid(_) => "✘".red().bold(),
             ProcessStatus::Stopped(_) => "?".yellow().bold(),
         };
-        println!(" {} {:+}", status_symbol, proc);
+        let mut notes = vec![];
+        if let Some(pid) = proc.get_pid() {
+            notes.push(format!("pid {}", pid));
+        }
+        if let Some(ref cmd) = proc.cmd_override {
+            notes.push(format!("running {:?}", cmd));
+        }
+        if proc.retries > 0 {
+            let max = proc
+                .max_retries
+                .map_or(String::new(), |max| format!(" of {}", max));
+            notes.push(format!("retry {}{}", proc.retries, max));
+        }
+        if proc.is_cwd_deleted() {
+            notes.push("cwd deleted".red().to_string());
+        }
+        if let Some(ref path) = proc.log_path {
+            notes.push(format!("log {}", path.display()));
+        }
+        match notes.is_empty() {
+            true => println!(" {} {:+}", status_symbol, proc),
+            false => println!(" {} {:+} ({})", status_symbol, proc, notes.join(", ")),
+        }
     })
 }
 
+fn purge(files: &[PathBuf], force: bool) -> Result<(), Box<dyn error::Error>> {
+    if files.is_empty() {
+        return Result::Ok(());
+    }
+
+    files
+        .iter()
+        .for_each(|file| println!("  {}", file.display()));
+    let confirmed = force
+        || Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Delete these log files?")
+            .interact()?;
+
+    if confirmed {
+        for file in files {
+            fs::remove_file(file)?;
+        }
+    }
+
+    Result::Ok(())
+}
+
+fn rotate_logs(state: &State, target: Option<&str>, keep: usize, signal: Option<&str>) -> MayError {
+    let signal = match signal {
+        Some(name) => Some(
+            system::parse_signal(name)
+                .ok_or_else(|| StateError::new(&format!("Unknown signal {:?}", name)))?,
+        ),
+        None => None,
+    };
+    let selected: Vec<&ProcessConfig> = state
+        .iter()
+        .filter(|proc| target.is_none_or(|target| proc.matches(target)))
+        .collect();
+    if let Some(target) = target.filter(|_| selected.is_empty()) {
+        return Result::Err(Box::new(StateError::new(&format!(
+            "No process matches {:?}",
+            target
+        ))));
+    }
+
+    let mut files: Vec<PathBuf> = selected.iter().flat_map(|proc| proc.log_files()).collect();
+    // The shared log belongs to everyone, it's only rotated along with all the others.
+    if target.is_none() {
+        files.extend(utils::get_output_path().ok().filter(|path| path.is_file()));
+    }
+    files.sort();
+    files.dedup();
+
+    for file in files {
+        utils::rotate_log(&file, keep)?;
+        println!("Rotated {}", file.display());
+    }
+
+    if let Some(signal) = signal {
+        for proc in selected.iter().filter(|proc| proc.is_running()) {
+            if let Some(pid) = proc.get_pid() {
+                if let Err(err) = system::send_signal(pid, signal) {
+                    eprintln!("Failed to signal {} at {}: {}", proc, pid, err);
+                }
+            }
+        }
+    }
+
+    Result::Ok(())
+}
+
+fn reap(state: &State, config: &Config, dry_run: bool, force: bool) -> MayError {
+    let leaked = state.leaked();
+    if leaked.is_empty() {
+        println!("No leaked processes");
+        return Result::Ok(());
+    }
+
+    leaked
+        .iter()
+        .for_each(|(name, proc)| println!("  {} at {} [{}]", name, proc.pid, proc.cmd));
+    if dry_run {
+        return Result::Ok(());
+    }
+    if config.safe {
+        return Result::Err(Box::new(StateError::new(
+            "Not killing processes watchman doesn't track (--safe)",
+        )));
+    }
+
+    let confirmed = force
+        || Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Kill these processes?")
+            .interact()?;
+
+    if confirmed {
+        for (name, proc) in leaked {
+            if !system::kill_by_pid(proc.pid) {
+                eprintln!("Failed to kill {} at {}", name, proc.pid);
+            }
+        }
+    }
+
+    Result::Ok(())
+}
+
 fn main() -> Result<(), Box<dyn error::Error>> {
     let args = Cli::from_args();
+    // Neither needs a state file of its own, don't resolve or create one for them.
+    match args.cmd {
+        Some(SubCommand::Version { json }) => return print_version(json),
+        Some(SubCommand::DiffConfig { ref old, ref new }) => return diff_config(old, new),
+        _ => {}
+    }
 
-    let file_input: PathBuf = args.state_path.unwrap_or(get_state_path()?);
-    let state_path = fs::canonicalize(file_input)?;
+    let mut state_paths = args.state_paths.iter();
+    let env_path = std::env::var_os(STATE_VAR).map(PathBuf::from);
+    let file_input: PathBuf = match state_paths.next().cloned().or(env_path) {
+        Some(path) => path,
+        // The wizard is for the interactive list, other commands are likely to be scripted.
+        None if args.cmd.is_none() && !args.no_wizard && wizard::is_interactive() => {
+            let default_path = utils::get_default_state_path()?;
+            if default_path.is_file() {
+                default_path
+            } else {
+                wizard::run(&default_path)?
+            }
+        }
+        None => get_state_path()?,
+    };
+    let state_path = utils::canonicalize_file(&file_input)
+        .map_err(|err| format!("Can't use state file {:?}: {}", file_input, err))?;
+    let read_only = args.cmd.as_ref().is_some_and(SubCommand::is_read_only);
+    if !read_only {
+        StateFile::check_writable(&state_path)?;
+    }
+    let mut file = StateFile::from_file(&state_path)?;
+    for layer_path in state_paths {
+        let layer_path = fs::canonicalize(layer_path)
+            .map_err(|err| format!("Can't read state file {:?}: {}", layer_path, err))?;
+        file.merge(StateFile::from_file(&layer_path)?);
+    }
+    file.config.safe = args.safe;
+    // Every "watch" starts a new session, unless it's just the upgraded binary taking over.
+    let upgraded = std::env::var_os(supervise::UPGRADE_VAR).is_some();
+    std::env::remove_var(supervise::UPGRADE_VAR);
+    let watching = matches!(args.cmd, Some(SubCommand::Watch { .. }));
+    file.config.daemon = watching;
+    if !read_only && (file.run_id.is_none() || (watching && !upgraded)) {
+        file.run_id = Some(utils::new_run_id());
+    }
+    if file.config.inject_run_id {
+        file.config.run_id = file.run_id.clone();
+    }
+    let mut state: State = std::mem::take(&mut file.processes);
+    let config = &file.config;
+    let mut persist = |state: &State| file.to_file_with(&state_path, state);
+    // As recorded, for post_flight once the command has stopped everything.
+    let was_running = state.iter().any(ProcessConfig::is_running);
+    let pids_before: Vec<i32> = state.iter().filter_map(ProcessConfig::get_pid).collect();
+    let attached = !watching && (args.foreground || !config.detach_on_exit());
 
     match args.cmd {
         Some(subcommand) => match subcommand {
-            SubCommand::Run { command, output } => {
+            SubCommand::Run {
+                command,
+                output,
+                restart_jitter,
+                watch,
+                watch_interval,
+                ignore,
+                log_buffer,
+                overflow,
+                tag,
+            } => {
                 let s = dbg!(system::join(command));
 
-                let output = output.and_then(|val| Some(PathBuf::from(val)));
+                let output = output.map(PathBuf::from);
                 let output: Option<&PathBuf> = output.as_ref();
-                system::keep_running_from_string(&s, output)?;
+                let options = system::KeepRunningOptions {
+                    restart_jitter,
+                    watch,
+                    watch_interval: Duration::from_millis(watch_interval),
+                    ignore,
+                    log_buffer,
+                    overflow,
+                    tag,
+                };
+                system::keep_running_from_string(&s, output, &options)?;
+            }
+            SubCommand::Add {
+                command,
+                name,
+                cwd,
+                args,
+                env,
+                env_file,
+                reload_signal,
+                wrapper,
+                health_check,
+                readiness_check,
+                liveness_check,
+                port,
+                verify_port,
+                verify_adopted,
+                on_restart,
+                restart,
+                max_retries,
+                restart_backoff,
+                parent_pid,
+                critical,
+                priority,
+                depends_on,
+                restart_dependents_on_dependency_restart,
+                wait,
+                wait_timeout,
+                stop_signal,
+                stop_timeout,
+                no_capture,
+            } => {
+                let process = ProcessConfig {
+                    cwd,
+                    args,
+                    env: env.into_iter().collect(),
+                    env_file,
+                    reload_signal,
+                    wrapper,
+                    health_check,
+                    readiness_check,
+                    liveness_check,
+                    port,
+                    verify_port,
+                    verify_adopted,
+                    on_restart,
+                    restart,
+                    max_retries,
+                    restart_backoff,
+                    parent_pid,
+                    critical,
+                    priority,
+                    depends_on,
+                    restart_dependents_on_dependency_restart,
+                    stop_signal,
+                    stop_timeout,
+                    capture_output: !no_capture,
+                    ..ProcessConfig::new(command, name)
+                };
+                let wait = Some(Duration::from_secs(wait_timeout)).filter(|_| wait);
+                state.add(process, config, wait, &mut persist)?;
+            }
+            SubCommand::Remove {
+                target,
+                purge_logs,
+                force,
+            } => {
+                // Vec has an inherent `remove` taking an index.
+                let removed = StateTrait::remove(&mut state, &target, config)?;
+                persist(&state)?;
+
+                let files: Vec<PathBuf> = removed
+                    .iter()
+                    .filter(|process| purge_logs || process.purge_logs_on_remove)
+                    .flat_map(|process| process.log_files_with_rotations())
+                    .collect();
+                removed
+                    .iter()
+                    .for_each(|process| println!("Removed {:+}", process));
+                purge(&files, force)?;
+            }
+            SubCommand::Inspect { target } => {
+                state.update_all();
+                inspect(&state, &target)?;
+            }
+            SubCommand::Snapshot { path } => {
+                state.update_all();
+                let path = path.unwrap_or_else(snapshot::default_path);
+                snapshot::write(&path, &snapshot::snapshot(&state, config))?;
+                println!("Wrote {}", path.display());
+            }
+            SubCommand::Config { cmd: None } => println!("{}", state_path.to_str().unwrap()),
+            SubCommand::Config {
+                cmd: Some(ConfigCommand::Effective),
+            } => show_effective(&state, config),
+            // Handled before the state file is loaded.
+            SubCommand::Version { .. } | SubCommand::DiffConfig { .. } => {}
+            SubCommand::Fix { selection } => {
+                supervise::pre_flight(config)?;
+                let indexes = selection.indexes(&state);
+                let report = state.fix_selected(&indexes, config, &mut persist)?;
+                report_outcomes(&report);
+                show(&state);
+                persist(&state)?;
+            }
+            SubCommand::Bench {
+                runs,
+                timeout,
+                selection,
+            } => {
+                let indexes = selection.indexes(&state);
+                let timeout = Duration::from_secs(timeout);
+                let report =
+                    bench::bench(&mut state, &indexes, config, runs, timeout, &mut persist)?;
+                show_bench(&report);
+                persist(&state)?;
+            }
+            SubCommand::RestartAll { selection } => {
+                supervise::pre_flight(config)?;
+                let indexes = selection.indexes(&state);
+                let report = state.restart_selected(&indexes, config, &mut persist)?;
+                report_outcomes(&report);
+                show(&state);
+                persist(&state)?;
+            }
+            SubCommand::Start {
+                target,
+                all,
+                selection,
+                override_cmd,
+            } => {
+                let indexes = targets(&state, target, all, &selection)?;
+                let report = state.start(&indexes, override_cmd, config, &mut persist)?;
+                report_outcomes(&report);
+                show(&state);
+            }
+            SubCommand::Stop {
+                target,
+                all,
+                selection,
+            } => {
+                let indexes = targets(&state, target, all, &selection)?;
+                let report = state.stop(&indexes, config, &mut persist)?;
+                for (name, outcome) in report {
+                    match outcome {
+                        Some(StopOutcome::Exited) => println!("stopped: {}", name),
+                        Some(StopOutcome::Killed) => println!("killed: {}", name),
+                        None => {}
+                    }
+                }
+                show(&state);
+            }
+            SubCommand::Restart {
+                target,
+                all,
+                selection,
+            } => {
+                let indexes = targets(&state, target, all, &selection)?;
+                let report = state.restart(&indexes, config, &mut persist)?;
+                report_outcomes(&report);
+                // With --all the selection is all there is to restart.
+                if !all {
+                    for index in indexes {
+                        for dependent in state.restart_dependents(index, config, &mut persist)? {
+                            println!("restarted: {} (depends on {})", dependent, state[index]);
+                        }
+                    }
+                }
+                show(&state);
+                persist(&state)?;
+            }
+            SubCommand::Solo { target } => {
+                // Keep the original set when soloing again without "unsolo" in between.
+                if file.solo.is_none() {
+                    file.solo = Some(state.enabled_names());
+                }
+                let mut persist = |state: &State| file.to_file_with(&state_path, state);
+                persist(&state)?;
+                state.solo(&target, config, &mut persist)?;
+                show(&state);
+                persist(&state)?;
+            }
+            SubCommand::Unsolo => {
+                let enabled = file.solo.clone().ok_or_else(|| {
+                    StateError::new("Nothing to restore, \"watchman solo\" wasn't used")
+                })?;
+                let mut persist = |state: &State| file.to_file_with(&state_path, state);
+                state.restore(&enabled, config, &mut persist)?;
+                show(&state);
+                file.solo = None;
+                file.to_file_with(&state_path, &state)?;
+            }
+            SubCommand::Watch {
+                log_format,
+                once: true,
+                ..
+            } => {
+                supervise::pre_flight(config)?;
+                let events = Events::new(log_format);
+                let down = supervise::watch_once(&mut state, config, &events, &mut persist)?;
+                if !down.is_empty() {
+                    down.iter().for_each(|name| eprintln!("Down: {}", name));
+                    std::process::exit(1);
+                }
+            }
+            SubCommand::Watch {
+                interval,
+                log_format,
+                once: false,
+            } => {
+                supervise::pre_flight(config)?;
+                let events = Events::new(log_format);
+                supervise::watch(
+                    &mut state,
+                    config,
+                    Duration::from_secs(interval),
+                    &utils::get_watch_pid_path(&state_path),
+                    &events,
+                    &mut persist,
+                )?;
             }
-            SubCommand::Add { command, name } => {
-                let mut state: State = State::from_file(&state_path)?;
-                state.add(command, name, None)?;
-                state.to_file(&state_path)?;
+            SubCommand::Reap { dry_run, force } => {
+                // Refreshing only, whatever would be adopted counts as leaked too.
+                state.refresh_all();
+                reap(&state, config, dry_run, force)?;
+                persist(&state)?;
             }
-            SubCommand::Config => println!("{}", state_path.to_str().unwrap()),
-            SubCommand::Fix => {
-                let mut state: State = State::from_file(&state_path)?;
-                state.fix_all()?;
+            SubCommand::Import { path } => {
+                import::run(&mut state, &path, config, &mut persist)?;
                 show(&state);
-                state.to_file(&state_path)?;
             }
-            SubCommand::Show => {
-                let mut state: State = State::from_file(&state_path)?;
+            SubCommand::Logs {
+                target,
+                lines,
+                follow,
+            } => {
+                let mut paths = vec![];
+                for index in state.matching(&target)? {
+                    paths.push(logs::log_of(&state[index])?);
+                }
+                paths.dedup();
+                logs::print(&paths, lines, follow)?;
+            }
+            SubCommand::RotateLogs {
+                target,
+                keep,
+                signal,
+            } => {
+                state.refresh_all();
+                rotate_logs(&state, target.as_deref(), keep, signal.as_deref())?;
+            }
+            SubCommand::ReloadEnv { target } => {
+                reload_env(&mut state, &target, config)?;
+                persist(&state)?;
+            }
+            SubCommand::Task { name, keep_going } => match name {
+                Some(name) => task::run(&file.tasks, &name, keep_going)?,
+                None => task::list(&file.tasks),
+            },
+            SubCommand::Usage => {
                 state.update_all();
+                show_usage(&state);
+            }
+            SubCommand::CheckPaths => {
+                let problems = paths::check(&state, config);
+                if !problems.is_empty() {
+                    problems.iter().for_each(|problem| eprintln!("{}", problem));
+                    std::process::exit(1);
+                }
+                println!("All paths are fine");
+            }
+            SubCommand::RunId => match file.run_id {
+                Some(ref run_id) => println!("{}", run_id),
+                None => return Err(Box::new(StateError::new("No session has been started yet"))),
+            },
+            SubCommand::Upgrade => {
+                let pid = supervise::upgrade(&utils::get_watch_pid_path(&state_path))?;
+                println!("Asked watchman watch at {} to upgrade", pid);
+            }
+            SubCommand::Repair => {
+                state.refresh_all();
                 show(&state);
-                state.to_file(&state_path)?;
+                persist(&state)?;
+            }
+            SubCommand::Show { json } => {
+                state.update_all();
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&state)?);
+                } else {
+                    show(&state);
+                }
             }
         },
         None => {
-            let mut state: State = State::from_file(&state_path)?;
-            state = interactive(state)?;
-            state.to_file(&state_path)?;
+            state = interactive(state, config, &mut persist)?;
+            persist(&state)?;
         }
     }
 
+    // Adopted processes weren't started by this command, they're left alone.
+    let spawned: Vec<usize> = (0..state.len())
+        .filter(|index| state[*index].is_running() && !state[*index].adopted)
+        .filter(|index| {
+            !state[*index]
+                .get_pid()
+                .is_some_and(|pid| pids_before.contains(&pid))
+        })
+        .collect();
+    if attached && !read_only && !spawned.is_empty() {
+        let mut persist = |state: &State| file.to_file_with(&state_path, state);
+        supervise::attach(&mut state, &spawned, config, &mut persist)?;
+    }
+
+    let was_running = was_running || !spawned.is_empty();
+    if !read_only && was_running && !state.iter().any(ProcessConfig::is_running) {
+        supervise::post_flight(config)?;
+    }
+
     Ok(())
 }
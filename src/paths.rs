@@ -0,0 +1,125 @@
+use std::ffi::CString;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::state::State;
+
+/// A path in the state file that would make starting a process fail.
+#[derive(Debug)]
+pub struct Problem {
+    /// Process the path belongs to, "config" for global settings.
+    pub owner: String,
+    pub setting: &'static str,
+    pub path: PathBuf,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} {:?} {}",
+            self.owner, self.setting, self.path, self.reason
+        )
+    }
+}
+
+fn is_writable(path: &Path) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+/// Why a file can't be created or written at `path`, if it can't.
+fn check_file(path: &Path) -> Option<&'static str> {
+    if path.is_dir() {
+        return Some("is a directory");
+    }
+    if path.exists() {
+        return if is_writable(path) {
+            None
+        } else {
+            Some("isn't writable")
+        };
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => return Some("has no parent directory"),
+    };
+    if !parent.exists() {
+        Some("is in a directory that doesn't exist")
+    } else if !parent.is_dir() {
+        Some("is in something that isn't a directory")
+    } else if !is_writable(parent) {
+        Some("is in a directory that isn't writable")
+    } else {
+        None
+    }
+}
+
+/// Every problem with the paths `state` and `config` refer to, not just the first one.
+pub fn check(state: &State, config: &Config) -> Vec<Problem> {
+    let mut files = vec![];
+    if let Some(ref path) = config.socket_path {
+        files.push(("config".to_string(), "socket_path", path.clone()));
+    }
+    for process in state.iter() {
+        if let Some(ref path) = process.output {
+            if process.capture_output {
+                files.push((process.to_string(), "output", path.clone()));
+            }
+        }
+    }
+
+    let cwds = state.iter().filter_map(|process| {
+        let path = process.cwd.as_ref()?;
+        let reason = if !path.exists() {
+            "doesn't exist"
+        } else if !path.is_dir() {
+            "isn't a directory"
+        } else {
+            return None;
+        };
+        Some(Problem {
+            owner: process.to_string(),
+            setting: "cwd",
+            path: path.clone(),
+            reason,
+        })
+    });
+    let env_files = state.iter().filter_map(|process| {
+        let path = process.env_file.as_ref()?;
+        let reason = if !path.exists() {
+            "doesn't exist"
+        } else if !path.is_file() {
+            "isn't a file"
+        } else {
+            return None;
+        };
+        Some(Problem {
+            owner: process.to_string(),
+            setting: "env_file",
+            path: path.clone(),
+            reason,
+        })
+    });
+
+    files
+        .into_iter()
+        .filter_map(|(owner, setting, path)| {
+            check_file(&path).map(|reason| Problem {
+                owner,
+                setting,
+                path,
+                reason,
+            })
+        })
+        .chain(cwds)
+        .chain(env_files)
+        .collect()
+}
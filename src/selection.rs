@@ -0,0 +1,90 @@
+use structopt::StructOpt;
+
+use crate::state::ProcessConfig;
+use crate::state::State;
+use crate::utils::glob_match;
+
+/// Which processes a bulk command applies to, all of them by default.
+#[derive(Debug, Default, StructOpt)]
+pub struct Selection {
+    /// Only processes matching these names, glob patterns or tags, comma separated, e.g.
+    /// 'web-*' or 'tag:backend'
+    #[structopt(long = "only", raw(use_delimiter = "true"))]
+    pub only: Vec<String>,
+    /// Leaves out processes matching these names, glob patterns or tags, comma separated
+    #[structopt(long = "except", raw(use_delimiter = "true"))]
+    pub except: Vec<String>,
+}
+
+/// Prefix of patterns matching `tags` instead of names.
+const TAG_PREFIX: &str = "tag:";
+
+fn matches_any(patterns: &[String], process: &ProcessConfig) -> bool {
+    let name = process.to_string();
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_prefix(TAG_PREFIX) {
+            Some(tag) => process.tags.iter().any(|own| glob_match(tag, own)),
+            None => process.matches(pattern) || glob_match(pattern, &name),
+        })
+}
+
+impl Selection {
+    /// Whether neither `--only` nor `--except` was given.
+    pub fn is_empty(&self) -> bool {
+        self.only.is_empty() && self.except.is_empty()
+    }
+
+    pub fn is_selected(&self, process: &ProcessConfig) -> bool {
+        (self.only.is_empty() || matches_any(&self.only, process))
+            && !matches_any(&self.except, process)
+    }
+
+    /// Indexes of the selected processes, warns when filters leave nothing.
+    pub fn indexes(&self, state: &State) -> Vec<usize> {
+        let indexes: Vec<usize> = (0..state.len())
+            .filter(|index| self.is_selected(&state[*index]))
+            .collect();
+        if indexes.is_empty() && !state.is_empty() {
+            eprintln!("No processes selected by --only/--except");
+        }
+        indexes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(name: &str, tags: &[&str]) -> ProcessConfig {
+        ProcessConfig {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..ProcessConfig::new("sleep 1".to_string(), Some(name.to_string()))
+        }
+    }
+
+    fn selection(only: &[&str], except: &[&str]) -> Selection {
+        Selection {
+            only: only.iter().map(|pattern| pattern.to_string()).collect(),
+            except: except.iter().map(|pattern| pattern.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn selects_by_name_glob_and_tag() {
+        let state = vec![
+            process("web-1", &["frontend"]),
+            process("web-2", &[]),
+            process("db", &["backend", "storage"]),
+        ];
+
+        assert_eq!(selection(&[], &[]).indexes(&state), vec![0, 1, 2]);
+        assert_eq!(selection(&["web-*"], &["web-2"]).indexes(&state), vec![0]);
+        assert_eq!(selection(&["tag:back*"], &[]).indexes(&state), vec![2]);
+        assert_eq!(
+            selection(&[], &["tag:frontend", "db"]).indexes(&state),
+            vec![1]
+        );
+        assert!(selection(&["tag:web-1"], &[]).indexes(&state).is_empty());
+    }
+}
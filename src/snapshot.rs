@@ -0,0 +1,70 @@
+use chrono::Local;
+use serde_json::json;
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::state::MayError;
+use crate::state::ProcessConfig;
+use crate::state::State;
+use crate::system;
+use crate::utils;
+use crate::version;
+
+/// Lines of each log included in a snapshot.
+const LOG_TAIL_LINES: usize = 50;
+
+/// `watchman-snapshot-<date>-<time>.json` in the current directory.
+pub fn default_path() -> PathBuf {
+    PathBuf::from(format!(
+        "watchman-snapshot-{}.json",
+        Local::now().format("%Y%m%d-%H%M%S")
+    ))
+}
+
+fn process_report(process: &ProcessConfig) -> Value {
+    let stats = process
+        .get_pid()
+        .filter(|_| process.is_running())
+        .and_then(system::get_stats)
+        .map(|stats| {
+            json!({
+                "memory_kb": stats.memory_kb,
+                "uptime_secs": stats.uptime.as_secs(),
+            })
+        });
+    let log_tail = process
+        .log_path
+        .as_ref()
+        .or(process.output.as_ref())
+        .and_then(|path| utils::read_tail(path, LOG_TAIL_LINES));
+
+    json!({
+        "name": process.to_string(),
+        "cmd": process.cmd,
+        "effective_cmd": process.effective_cmd(),
+        "status": process.status,
+        "pid": process.get_pid(),
+        "adopted": process.adopted,
+        "restarts": process.restarts,
+        "stats": stats,
+        "log_path": process.log_path,
+        "log_tail": log_tail,
+    })
+}
+
+/// Everything worth attaching to a bug report, `state` should be freshly updated.
+pub fn snapshot(state: &State, config: &Config) -> Value {
+    json!({
+        "taken_at": Local::now().to_rfc3339(),
+        "watchman": version::info(),
+        "config": config,
+        "processes": state.iter().map(process_report).collect::<Vec<Value>>(),
+    })
+}
+
+pub fn write(path: &PathBuf, snapshot: &Value) -> MayError {
+    fs::write(path, serde_json::to_string_pretty(snapshot)?)?;
+    Result::Ok(())
+}
@@ -0,0 +1,173 @@
+use serde_json::{json, Value};
+use std::error;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::events::Events;
+use crate::state::Persist;
+use crate::state::State;
+use crate::state::StateError;
+use crate::state::StateTrait;
+
+/// How long a client may take to send a request before it's dropped, the watch loop waits
+/// for it meanwhile.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long clients get per watch pass, so a busy one can't hold up restarts. Whoever is
+/// connected when it runs out is hung up on after its current request.
+const SERVE_BUDGET: Duration = Duration::from_secs(2);
+
+/// Control socket of `watchman watch`, see "Control socket" in the readme for the protocol.
+pub struct Server {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Server {
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        // Left behind by a watch that crashed or was upgraded.
+        if UnixStream::connect(path).is_err() && path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Server {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Handles every client waiting to be accepted, returns right away if there are none and
+    /// at the latest after `SERVE_BUDGET`.
+    pub fn serve(
+        &self,
+        state: &mut State,
+        config: &Config,
+        events: &Events,
+        persist: &mut Persist,
+    ) {
+        let deadline = Instant::now() + SERVE_BUDGET;
+        while Instant::now() < deadline {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    events.emit("socket_failed", None, json!({ "error": err.to_string() }));
+                    return;
+                }
+            };
+            if let Err(err) = handle(stream, deadline, state, config, events, persist) {
+                events.emit("socket_failed", None, json!({ "error": err.to_string() }));
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Answers every request line of a client with a response line, until it hangs up or
+/// `deadline` has passed.
+fn handle(
+    stream: UnixStream,
+    deadline: Instant,
+    state: &mut State,
+    config: &Config,
+    events: &Events,
+    persist: &mut Persist,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match execute(line.trim(), state, config, persist) {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(err) => json!({ "ok": false, "error": err.to_string() }),
+        };
+        // Without the result, a state request would repeat the whole state.
+        events.emit(
+            "socket_request",
+            None,
+            json!({ "request": line.trim(), "ok": response["ok"], "error": response["error"] }),
+        );
+        writeln!(writer, "{}", response)?;
+        if Instant::now() >= deadline {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn execute(
+    request: &str,
+    state: &mut State,
+    config: &Config,
+    persist: &mut Persist,
+) -> Result<Value, Box<dyn error::Error>> {
+    let (command, target) = match request.find(' ') {
+        Some(at) => (&request[..at], request[at + 1..].trim()),
+        None => (request, ""),
+    };
+    let result = match command {
+        "state" => {
+            state.update_all();
+            return Ok(serde_json::to_value(&*state)?);
+        }
+        "start" | "stop" | "restart" if target.is_empty() => {
+            return Err(Box::new(StateError::new(&format!(
+                "{} needs a process name",
+                command
+            ))))
+        }
+        "start" => outcomes(state.start(&state.matching(target)?, None, config, persist)?),
+        "stop" => {
+            let report = state.stop(&state.matching(target)?, config, persist)?;
+            outcomes(
+                report
+                    .into_iter()
+                    .map(|(name, outcome)| {
+                        (
+                            name,
+                            outcome.map_or("not running".to_string(), |o| o.to_string()),
+                        )
+                    })
+                    .collect(),
+            )
+        }
+        "restart" => outcomes(state.restart(&state.matching(target)?, config, persist)?),
+        _ => {
+            return Err(Box::new(StateError::new(&format!(
+                "Unknown command {:?}, expected state, start, stop or restart",
+                command
+            ))))
+        }
+    };
+    persist(state)?;
+
+    Ok(result)
+}
+
+fn outcomes<T: ToString>(report: Vec<(String, T)>) -> Value {
+    report
+        .into_iter()
+        .map(|(name, outcome)| json!({ "process": name, "outcome": outcome.to_string() }))
+        .collect()
+}
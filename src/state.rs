@@ -1,50 +1,339 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::error;
 use std::fmt;
 use std::fs;
 use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::config::Config;
+use crate::config::Resolved;
+use crate::config::{DEFAULT_LOG_NAME_TEMPLATE, DEFAULT_STOP_SIGNAL, DEFAULT_STOP_TIMEOUT};
+use crate::health;
 use crate::system;
 use crate::system::get_by_pid;
 use crate::system::run_from_string;
+use crate::system::Output;
+use crate::system::StopOutcome;
 use crate::utils;
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(from = "StoredStatus")]
 pub enum ProcessStatus {
     /// Process is not expected to run.
+    #[default]
     Disabled,
-    /// Expecting the process to be running with pid.
-    Running(i32),
+    /// Expecting the process to be running with pid, as what was there when it was last seen.
+    Running(i32, Option<Identity>),
     /// Expected process is not running, but there is another one with pid.
     Invalid(i32),
     /// Expected process is not running, there is nothing with pid.
     Stopped(i32),
 }
 
-impl Default for ProcessStatus {
-    fn default() -> Self {
-        ProcessStatus::Disabled
+impl ProcessStatus {
+    /// The pid of a process expected to be running.
+    pub fn running_pid(&self) -> Option<i32> {
+        match self {
+            ProcessStatus::Running(pid, _) => Some(*pid),
+            _ => None,
+        }
+    }
+}
+
+/// Tells a process apart from a later one that got its pid.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub struct Identity {
+    /// Seconds since boot.
+    pub start_time: u64,
+    pub uid: u32,
+}
+
+impl Identity {
+    fn of(proc: &system::Process) -> Self {
+        Identity {
+            start_time: proc.start_time,
+            uid: proc.uid,
+        }
+    }
+}
+
+/// `ProcessStatus` as read from state files, which only had the pid of running processes
+/// before.
+#[derive(Deserialize)]
+enum StoredStatus {
+    Disabled,
+    Running(StoredRunning),
+    Invalid(i32),
+    Stopped(i32),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredRunning {
+    Identified(i32, Option<Identity>),
+    Pid(i32),
+}
+
+impl From<StoredStatus> for ProcessStatus {
+    fn from(status: StoredStatus) -> Self {
+        match status {
+            StoredStatus::Disabled => ProcessStatus::Disabled,
+            StoredStatus::Running(StoredRunning::Identified(pid, identity)) => {
+                ProcessStatus::Running(pid, identity)
+            }
+            StoredStatus::Running(StoredRunning::Pid(pid)) => ProcessStatus::Running(pid, None),
+            StoredStatus::Invalid(pid) => ProcessStatus::Invalid(pid),
+            StoredStatus::Stopped(pid) => ProcessStatus::Stopped(pid),
+        }
+    }
+}
+
+pub type MayError = Result<(), Box<dyn error::Error>>;
+
+/// How each stopped process went, `None` for the ones that weren't running.
+pub type StopReport = Vec<(String, Option<StopOutcome>)>;
+
+/// How a process came to be running after being started.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunOutcome {
+    /// It was running with the recorded pid already.
+    AlreadyRunning(i32),
+    /// Found running by command and taken over.
+    Adopted(i32),
+    /// Spawned by watchman.
+    Started(i32),
+}
+
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunOutcome::AlreadyRunning(pid) => write!(f, "running (pid {})", pid),
+            RunOutcome::Adopted(pid) => write!(f, "adopted (pid {})", pid),
+            RunOutcome::Started(pid) => write!(f, "started (pid {})", pid),
+        }
     }
 }
 
-type MayError = Result<(), Box<dyn error::Error>>;
+/// When `watchman watch` restarts a process that has stopped.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Always,
+    /// Only after a non-zero or unknown exit code, or a failed liveness check.
+    OnFailure,
+    Never,
+}
+
+impl RestartPolicy {
+    fn is_always(&self) -> bool {
+        *self == RestartPolicy::Always
+    }
+}
+
+impl FromStr for RestartPolicy {
+    type Err = StateError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "always" => Ok(RestartPolicy::Always),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "never" => Ok(RestartPolicy::Never),
+            _ => Err(StateError::new(&format!(
+                "Unknown restart policy {:?}, expected \"always\", \"on-failure\" or \"never\"",
+                input
+            ))),
+        }
+    }
+}
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+/// Writes the state out. Called right after a process is spawned so the new
+/// pid is on disk even if watchman dies before the end of the session.
+pub type Persist<'a> = dyn FnMut(&State) -> MayError + 'a;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
 pub struct ProcessConfig {
     pub name: Option<String>,
     pub cmd: String,
     #[serde(default)]
     pub status: ProcessStatus,
     pub output: Option<PathBuf>,
+    /// Redirect output to `output` or the default log. When off the process writes to
+    /// wherever watchman's own output goes.
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub capture_output: bool,
+    /// Command the current instance was started with instead of `cmd` and `args`, see
+    /// `watchman start`. Cleared on the next normal start.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd_override: Option<String>,
+    /// Directory the process is started in, made absolute when it's started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Arguments passed after `cmd` as they are, unlike `cmd` they aren't split on spaces.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Env vars the process gets, over the ones from `env_file`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// File with `KEY=VALUE` lines the process gets as env vars.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_file: Option<PathBuf>,
+    /// Signal making the process re-read its `env_file`, e.g. `HUP`, see `watchman reload-env`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reload_signal: Option<String>,
+    /// Command prefix the process is launched under, e.g. `strace -f`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wrapper: Option<String>,
+    /// Shell command that exits with 0 when the process is healthy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<String>,
+    /// Shell command that exits with 0 once the process is ready to be used. Gates the
+    /// start of its dependents, falls back to `health_check`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readiness_check: Option<String>,
+    /// Shell command `watchman watch` runs every pass, the process is restarted when it fails.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liveness_check: Option<String>,
+    /// Local TCP port the process is expected to listen on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Only consider the process ready and live while something listens on `port`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub verify_port: bool,
+    /// Run `health_check` before adopting an already running process.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub verify_adopted: bool,
+    /// Shell command run by `watchman watch` before restarting a process that died.
+    /// Gets the exit code (or "unknown") and the restart count as arguments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_restart: Option<String>,
+    /// How many times `watchman watch` has restarted the process.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub restarts: u32,
+    /// When `watchman watch` restarts the process once it has stopped.
+    #[serde(default, skip_serializing_if = "RestartPolicy::is_always")]
+    pub restart: RestartPolicy,
+    /// Restarts in a row `watchman watch` makes before giving up and disabling the process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    /// Seconds to wait before restarting a process that has died again since its last
+    /// restart, doubled with every retry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restart_backoff: Option<u64>,
+    /// Restarts by `watchman watch` since it last found the process running.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retries: u32,
+    /// Delete the log files when the process is removed.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub purge_logs_on_remove: bool,
+    /// Signal the process is stopped with, e.g. `TERM`. Falls back to `config.stop_signal`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_signal: Option<String>,
+    /// Seconds to wait after `stop_signal` before killing. Falls back to `config.stop_timeout`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_timeout: Option<u64>,
+    /// Names of processes this one needs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+    /// Restart this process too whenever one of `depends_on` is restarted.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub restart_dependents_on_dependency_restart: bool,
+    /// Exempt from `max_running`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub critical: bool,
+    /// Processes with a higher priority are restarted first when several are down at once.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: u32,
+    /// Labels for picking groups of processes, e.g. `--only tag:backend`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// `watchman watch` stops the process once this pid is gone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_pid: Option<i32>,
+    /// Keep the end of the output in `last_output`, meant for processes printing a
+    /// status line now and then, not for chatty ones.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub capture_output_inline: bool,
+    /// Most bytes kept in `last_output`, 1024 by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inline_output_limit: Option<u64>,
+    /// End of the log, kept up to date by watchman with `capture_output_inline`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_output: Option<String>,
+    /// The running instance was found by command rather than spawned by watchman. Always
+    /// stored, entries of older state files without it may be anyone's and count as adopted.
+    #[serde(default = "default_true")]
+    pub adopted: bool,
+    /// Where output went the last time the process was started, kept up to date by watchman.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_path: Option<PathBuf>,
+}
+
+/// Env var holding the supervisor session id, see `inject_run_id`.
+pub const RUN_ID_VAR: &str = "WATCHMAN_RUN_ID";
+
+/// Default of `inline_output_limit`.
+const INLINE_OUTPUT_LIMIT: u64 = 1024;
+
+/// How often `wait_until_ready` runs the readiness check.
+const READY_POLL: Duration = Duration::from_millis(500);
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+fn default_true() -> bool {
+    true
 }
 
 impl ProcessConfig {
-    fn get_pid(&self) -> Option<i32> {
+    pub fn new(cmd: String, name: Option<String>) -> Self {
+        ProcessConfig {
+            cmd,
+            name,
+            capture_output: true,
+            ..Default::default()
+        }
+    }
+
+    /// What gets spawned: a command line split on whitespace, followed by `args` as they are.
+    fn command_line(&self) -> (String, &[String]) {
+        let (cmd, args) = match self.cmd_override {
+            Some(ref cmd) => (cmd, &[][..]),
+            None => (&self.cmd, &self.args[..]),
+        };
+        let cmd = match self.wrapper {
+            Some(ref wrapper) => system::join(vec![wrapper.clone(), cmd.clone()]),
+            None => cmd.clone(),
+        };
+        (cmd, args)
+    }
+
+    /// The command as it shows up in the process list, override, wrapper and args included.
+    pub fn effective_cmd(&self) -> String {
+        let (cmd, args) = self.command_line();
+        system::join(std::iter::once(cmd).chain(args.iter().cloned()).collect())
+    }
+
+    pub fn get_pid(&self) -> Option<i32> {
         match self.status {
-            ProcessStatus::Running(proc)
+            ProcessStatus::Running(proc, _)
             | ProcessStatus::Invalid(proc)
             | ProcessStatus::Stopped(proc) => Some(proc),
             _ => None,
@@ -57,108 +346,476 @@ impl ProcessConfig {
             My initial ambition was to somehow have chained calls instead of nested ones,
             each one unwrapping another layer of Option.
         */
+        let cmd = self.effective_cmd();
         self.get_pid().map_or(ProcessStatus::Disabled, |pid| {
             get_by_pid(pid).map_or(ProcessStatus::Stopped(pid), |proc| {
-                if self.cmd == proc.cmd {
-                    ProcessStatus::Running(proc.pid)
-                } else {
-                    eprintln!(
-                        "Expected {:?}, saw {:?} at {}",
-                        self.cmd, proc.cmd, proc.pid
-                    );
-                    ProcessStatus::Invalid(proc.pid)
+                let identity = Identity::of(&proc);
+                match self.status {
+                    // Same command, but started later or by someone else: the pid was reused.
+                    ProcessStatus::Running(_, Some(seen)) if seen != identity => {
+                        eprintln!(
+                            "Pid {} of {} was reused, expected {:?}, saw {:?}",
+                            proc.pid, self, seen, identity
+                        );
+                        ProcessStatus::Invalid(proc.pid)
+                    }
+                    // Still someone else's, only adopting takes it over.
+                    ProcessStatus::Invalid(_) => ProcessStatus::Invalid(proc.pid),
+                    _ if cmd == proc.cmd => ProcessStatus::Running(proc.pid, Some(identity)),
+                    _ => {
+                        eprintln!("Expected {:?}, saw {:?} at {}", cmd, proc.cmd, proc.pid);
+                        ProcessStatus::Invalid(proc.pid)
+                    }
                 }
             })
         })
     }
 
-    fn update(&mut self) {
+    /// Re-checks the recorded pid without adopting anything found by command.
+    fn refresh(&mut self) {
         self.status = self.check_status();
+    }
+
+    fn update(&mut self) {
+        self.refresh();
+        self.capture_last_output();
 
         //? Is there a good way to refactor this method more functional?
         if self.is_enabled() && !self.is_running() {
             // have to keep them separate because "`let` expressions in this position are experimental"
-            if let Some(adopted_proc) = system::get_by_cmd(&self.cmd) {
-                self.status = ProcessStatus::Running(adopted_proc.pid);
+            if let Some(adopted_proc) = system::get_by_cmd(&self.effective_cmd()) {
+                if self.is_adoptable() {
+                    self.status =
+                        ProcessStatus::Running(adopted_proc.pid, Some(Identity::of(&adopted_proc)));
+                    self.adopted = true;
+                } else {
+                    eprintln!(
+                        "Not adopting {} at {}, it failed the health check",
+                        self, adopted_proc.pid
+                    );
+                }
             }
         }
     }
 
-    fn fix(&mut self) -> MayError {
-        if self.is_enabled() {
-            self.run()?
+    /// Copies the end of the log into `last_output` when `capture_output_inline` is set.
+    fn capture_last_output(&mut self) {
+        if !self.capture_output_inline {
+            return;
         }
+        let limit = self.inline_output_limit.unwrap_or(INLINE_OUTPUT_LIMIT);
+        if let Some(output) = self
+            .log_path
+            .as_ref()
+            .and_then(|path| utils::read_tail_bytes(path, limit))
+        {
+            self.last_output = Some(output);
+        }
+    }
 
-        Result::Ok(())
+    fn is_adoptable(&self) -> bool {
+        if !self.verify_adopted {
+            return true;
+        }
+
+        self.health_check.as_deref().is_none_or(health::check)
+    }
+
+    /// The port to probe, if `verify_port` is set.
+    fn verified_port(&self) -> Option<u16> {
+        self.port.filter(|_| self.verify_port)
     }
 
-    pub fn run(&mut self) -> MayError {
+    fn has_readiness_check(&self) -> bool {
+        self.readiness_check.is_some()
+            || self.health_check.is_some()
+            || self.verified_port().is_some()
+    }
+
+    /// Runs the readiness check, processes without one are always ready.
+    pub fn is_ready(&self) -> bool {
+        self.verified_port().is_none_or(health::is_listening)
+            && self
+                .readiness_check
+                .as_ref()
+                .or(self.health_check.as_ref())
+                .is_none_or(|cmd| health::check(cmd))
+    }
+
+    /// Runs the liveness check, processes without one are always live.
+    pub fn is_live(&self) -> bool {
+        self.verified_port().is_none_or(health::is_listening)
+            && self.liveness_check.as_deref().is_none_or(health::check)
+    }
+
+    fn default_output(
+        &self,
+        config: &Config,
+        pid: Option<i32>,
+    ) -> Result<Option<PathBuf>, Box<dyn error::Error>> {
+        let template = config
+            .log_name_template
+            .as_deref()
+            .unwrap_or(DEFAULT_LOG_NAME_TEMPLATE);
+        let path = utils::get_templated_output_path(template, &self.to_string(), pid)?;
+        Result::Ok(Some(path))
+    }
+
+    /// Moves a default log opened before the process had a pid to where `{pid}` says, the
+    /// process keeps writing to it. Returns where the log ends up.
+    fn place_log(&self, config: &Config, pending: PathBuf, pid: i32) -> PathBuf {
+        let path = match self.default_output(config, Some(pid)) {
+            Ok(Some(path)) if path != pending => path,
+            _ => return pending,
+        };
+        match fs::rename(&pending, &path) {
+            Ok(()) => path,
+            Err(err) => {
+                eprintln!("Can't move log of {} to {:?}: {}", self, path, err);
+                pending
+            }
+        }
+    }
+
+    /// Starts the process unless it's running already, a fresh spawn runs `cmd_override`
+    /// instead of `cmd` when given. Returns whether it was spawned.
+    pub fn run(
+        &mut self,
+        config: &Config,
+        cmd_override: Option<String>,
+    ) -> Result<bool, Box<dyn error::Error>> {
         self.update();
 
         if !self.is_running() {
-            //TODO: solve this better
-            let default_path_option = utils::get_output_path().ok();
-            let default_path = default_path_option.as_ref();
-            let logs_path = self.output.as_ref().or(default_path);
+            // Whatever update refused to adopt is wedged, restart it instead of running twice.
+            if self.verify_adopted {
+                if let Some(unhealthy) = system::get_by_cmd(&self.effective_cmd()) {
+                    if config.safe {
+                        return Result::Err(Box::new(StateError::new_with_safe_mode(
+                            self,
+                            unhealthy.pid,
+                        )));
+                    }
+                    println!("Stopping unhealthy {} at {}", self, unhealthy.pid);
+                    let (signal, timeout) = self.stop_settings(config)?;
+                    system::stop_by_pid(unhealthy.pid, signal, timeout);
+                }
+            }
 
-            let res = run_from_string(&self.cmd, logs_path)?;
-            self.status = ProcessStatus::Running(res);
+            self.resolve_paths()?;
+            let logs_path = match self.output {
+                _ if !self.capture_output => None,
+                Some(ref path) => Some(path.clone()),
+                None => self.default_output(config, None)?,
+            };
 
-            get_by_pid(res).map(|proc| {
-                if proc.cmd.len() < 2 {
-                    panic!("Empty cmd: {:?}", proc);
+            self.cmd_override = cmd_override;
+            self.adopted = false;
+            let cmd = self.effective_cmd();
+            let env_file = match self.env_file {
+                Some(ref path) => utils::read_env_file(path)?,
+                None => vec![],
+            };
+            // Later ones win, `env` over `env_file`.
+            let env: Vec<(&str, &str)> = env_file
+                .iter()
+                .map(|(key, value)| (key, value))
+                .chain(self.env.iter())
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .chain(config.run_id.iter().map(|id| (RUN_ID_VAR, id.as_str())))
+                .collect();
+            let (line, args) = self.command_line();
+            let cwd = self.cwd.as_deref();
+            let output = match logs_path {
+                Some(ref path) => Output::Log(path),
+                None if config.daemon => Output::Null,
+                None => Output::Inherit,
+            };
+            let res = run_from_string(&line, args, output, &env, cwd)?;
+            self.status = ProcessStatus::Running(res, None);
+            self.log_path = match logs_path {
+                Some(path) if self.output.is_none() => Some(self.place_log(config, path, res)),
+                logs_path => logs_path,
+            };
+
+            match system::wait_for_exec(res) {
+                Some(proc) if cmd == proc.cmd => {
+                    self.status = ProcessStatus::Running(res, Some(Identity::of(&proc)));
+                }
+                Some(proc) => {
+                    // It could never be told apart from something else reusing the pid, and
+                    // every later start would spawn another copy.
+                    system::kill_by_pid(res);
+                    self.status = ProcessStatus::Stopped(res);
+                    return Result::Err(Box::new(StateError::new(&format!(
+                        "{} showed up as {:?} instead of {:?} at {}, killed it",
+                        self, proc.cmd, cmd, res
+                    ))));
                 }
-                if self.cmd != proc.cmd {
-                    eprintln!("Current pid is {}", std::process::id());
-                    eprintln!("Result from run is {}", &res);
-                    panic!("Changed cmd: {:?} -> {:?}", self.cmd, proc);
+                None => {
+                    self.status = ProcessStatus::Stopped(res);
+                    return Result::Err(Box::new(self.startup_error(res)));
                 }
-            });
+            }
+            return Result::Ok(true);
         }
 
-        Result::Ok(())
+        Result::Ok(false)
     }
 
-    pub fn kill(&mut self) -> bool {
-        // println!("Killing {:?}", self.name);
-        self.update();
+    /// Pins `cwd` and `output` down to absolute, canonical paths, so they keep pointing at
+    /// the same place whichever directory watchman is run from later.
+    fn resolve_paths(&mut self) -> MayError {
+        if let Some(ref cwd) = self.cwd {
+            let resolved = fs::canonicalize(cwd)
+                .ok()
+                .filter(|resolved| resolved.is_dir())
+                .ok_or_else(|| {
+                    StateError::new(&format!("cwd {:?} of {} isn't a directory", cwd, self))
+                })?;
+            self.cwd = Some(resolved);
+        }
+        if let Some(ref output) = self.output {
+            // A parent that doesn't exist fails opening the log, with an error naming the path.
+            if let Ok(output) = utils::canonicalize_file(output) {
+                self.output = Some(output);
+            }
+        }
 
+        Result::Ok(())
+    }
+
+    /// Whether the process is running in a directory that has since been deleted.
+    pub fn is_cwd_deleted(&self) -> bool {
         match self.status {
-            ProcessStatus::Running(pid) => {
-                let res = system::kill_by_pid(pid);
-                // set disabled or... retry?
-                self.kill();
-                res
+            ProcessStatus::Running(pid, _) => system::is_cwd_deleted(pid),
+            _ => false,
+        }
+    }
+
+    /// Waits for the readiness check to pass, processes without one are ready right away.
+    pub fn wait_until_ready(&mut self, timeout: Duration) -> MayError {
+        if !self.has_readiness_check() {
+            return Result::Ok(());
+        }
+
+        let started = Instant::now();
+        loop {
+            self.refresh();
+            if let ProcessStatus::Stopped(pid) = self.status {
+                return Result::Err(Box::new(self.startup_error(pid)));
             }
-            ProcessStatus::Stopped(_) => {
-                self.status = ProcessStatus::Disabled;
-                false
+            if self.is_ready() {
+                return Result::Ok(());
             }
-            _ => false,
+            if started.elapsed() >= timeout {
+                return Result::Err(Box::new(StateError::new_with_not_ready(self, timeout)));
+            }
+            thread::sleep(READY_POLL);
         }
     }
 
-    pub fn is_running(&self) -> bool {
-        match self.status {
-            ProcessStatus::Running(_pid) => true,
-            _ => false,
+    /// Describes a process that died before startup verification could see it.
+    fn startup_error(&self, pid: i32) -> StateError {
+        let exit = match system::reap(pid) {
+            Some(code) => format!("with code {}", code),
+            None => "right away".to_string(),
+        };
+        let mut msg = format!("{} exited during startup {}", self, exit);
+
+        if let Some(tail) = self
+            .log_path
+            .as_ref()
+            .and_then(|path| utils::read_tail(path, 10))
+        {
+            msg.push_str("\nLast output:\n");
+            msg.push_str(&tail);
         }
+
+        StateError::new(&msg)
     }
 
-    pub fn is_enabled(&self) -> bool {
+    /// In safe mode only processes watchman spawned itself may be killed.
+    fn check_killable(&self, config: &Config) -> MayError {
         match self.status {
-            ProcessStatus::Disabled => false,
-            _ => true,
+            ProcessStatus::Running(pid, _) if config.safe && self.adopted => {
+                Result::Err(Box::new(StateError::new_with_safe_mode(self, pid)))
+            }
+            _ => Result::Ok(()),
+        }
+    }
+
+    pub fn stop_signal(&self, config: &Config) -> Resolved<String> {
+        Resolved::resolve(
+            self.stop_signal.clone(),
+            config.stop_signal.clone(),
+            DEFAULT_STOP_SIGNAL.to_string(),
+        )
+    }
+
+    pub fn stop_timeout(&self, config: &Config) -> Resolved<u64> {
+        Resolved::resolve(self.stop_timeout, config.stop_timeout, DEFAULT_STOP_TIMEOUT)
+    }
+
+    /// The stop signal and timeout to stop the process with.
+    fn stop_settings(&self, config: &Config) -> Result<(i32, Duration), StateError> {
+        let signal_name = self.stop_signal(config).value;
+        let signal = system::parse_signal(&signal_name)
+            .ok_or_else(|| StateError::new_with_signal(self, &signal_name))?;
+        Result::Ok((signal, Duration::from_secs(self.stop_timeout(config).value)))
+    }
+
+    /// Stops the process with its stop signal, killing it if it's still there after the stop
+    /// timeout. Returns how it went, `None` if it wasn't running.
+    /// Only the recorded pid is stopped, nothing is adopted by its command on the way.
+    pub fn kill(&mut self, config: &Config) -> Result<Option<StopOutcome>, Box<dyn error::Error>> {
+        self.refresh();
+        self.capture_last_output();
+        self.check_killable(config)?;
+
+        let outcome = match self.status {
+            ProcessStatus::Running(pid, _) => {
+                let (signal, timeout) = self.stop_settings(config)?;
+                let outcome = system::stop_by_pid(pid, signal, timeout);
+                // Retrying a process that survived KILL would never end.
+                self.refresh();
+                if self.is_running() {
+                    return Result::Err(Box::new(StateError::new(&format!(
+                        "{} is still running at {} after being killed",
+                        self, pid
+                    ))));
+                }
+                Some(outcome)
+            }
+            // An invalid pid belongs to someone else now, there's nothing of ours to stop.
+            ProcessStatus::Stopped(_) | ProcessStatus::Invalid(_) => None,
+            ProcessStatus::Disabled => return Result::Ok(None),
+        };
+        self.status = ProcessStatus::Disabled;
+        self.adopted = false;
+        Result::Ok(outcome)
+    }
+
+    /// Kills the process but keeps it enabled, so it's started again.
+    pub fn kill_for_restart(
+        &mut self,
+        config: &Config,
+    ) -> Result<Option<StopOutcome>, Box<dyn error::Error>> {
+        let pid = self.get_pid();
+        let res = self.kill(config)?;
+        if let Some(pid) = pid {
+            self.status = ProcessStatus::Stopped(pid);
         }
+        Result::Ok(res)
+    }
+
+    /// Gets a running process to pick up changes to its `env_file`: with a `reload_signal`
+    /// it's signalled to re-read it, otherwise restarted. Returns whether it was restarted.
+    pub fn reload_env(&mut self, config: &Config) -> Result<bool, Box<dyn error::Error>> {
+        let path = self
+            .env_file
+            .clone()
+            .ok_or_else(|| StateError::new(&format!("{} has no env_file", self)))?;
+        // A broken file would only fail the process once it's read.
+        utils::read_env_file(&path)?;
+
+        self.update();
+        let pid = match self.status {
+            ProcessStatus::Running(pid, _) => pid,
+            _ => return Result::Ok(false),
+        };
+        match self.reload_signal {
+            Some(ref name) => {
+                let signal = system::parse_signal(name).ok_or_else(|| {
+                    StateError::new(&format!("Unknown reload signal {:?} for {}", name, self))
+                })?;
+                system::send_signal(pid, signal)?;
+                Result::Ok(false)
+            }
+            None => {
+                self.kill_for_restart(config)?;
+                self.run(config, None)?;
+                Result::Ok(true)
+            }
+        }
+    }
+
+    /// Log files that belong to this process alone, the shared default log is never included.
+    pub fn log_files(&self) -> Vec<PathBuf> {
+        let shared = utils::get_output_path().ok();
+        let mut files: Vec<PathBuf> = self
+            .output
+            .iter()
+            .chain(self.log_path.iter())
+            .filter(|path| Some(*path) != shared.as_ref())
+            .filter(|path| path.is_file())
+            .cloned()
+            .collect();
+        files.dedup();
+        files
+    }
+
+    /// `log_files` along with their rotations.
+    pub fn log_files_with_rotations(&self) -> Vec<PathBuf> {
+        self.log_files()
+            .into_iter()
+            .flat_map(|path| {
+                let rotations = utils::rotated_logs(&path);
+                std::iter::once(path).chain(rotations)
+            })
+            .collect()
+    }
+
+    /// Whether `target` refers to this process: its name if it has one, otherwise its cmd.
+    pub fn matches(&self, target: &str) -> bool {
+        match self.name {
+            Some(ref name) => name == target,
+            None => self.cmd == target,
+        }
+    }
+
+    /// Whether `other` is a definition of the same process: same name, or same cmd when
+    /// neither has one.
+    fn is_same_process(&self, other: &ProcessConfig) -> bool {
+        self.name == other.name && (self.name.is_some() || self.cmd == other.cmd)
+    }
+
+    /// Takes over what watchman knows about `previous`'s instance, so replacing the
+    /// definition of a running process doesn't lose track of it.
+    fn with_runtime_of(self, previous: &ProcessConfig) -> Self {
+        ProcessConfig {
+            status: previous.status.clone(),
+            cmd_override: previous.cmd_override.clone(),
+            restarts: previous.restarts,
+            retries: previous.retries,
+            parent_pid: previous.parent_pid,
+            last_output: previous.last_output.clone(),
+            adopted: previous.adopted,
+            log_path: previous.log_path.clone(),
+            ..self
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(self.status, ProcessStatus::Running(..))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self.status, ProcessStatus::Disabled)
     }
 }
 
 impl fmt::Display for ProcessConfig {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if f.sign_plus() {
+            let cmd = system::join(
+                std::iter::once(self.cmd.clone())
+                    .chain(self.args.iter().cloned())
+                    .collect(),
+            );
             match self.name {
-                Some(ref name) => write!(f, "{name} [{cmd}]", name = name, cmd = self.cmd),
-                None => write!(f, "{}", self.cmd),
+                Some(ref name) => write!(f, "{name} [{cmd}]", name = name, cmd = cmd),
+                None => write!(f, "{}", cmd),
             }
         } else {
             match self.name {
@@ -171,28 +828,513 @@ impl fmt::Display for ProcessConfig {
 
 pub type ParseError = serde_json::error::Error;
 
-pub trait StateTrait<DS = Self> {
+#[derive(Debug)]
+pub struct StateError {
+    msg: String,
+}
+
+impl StateError {
+    pub fn new(msg: &str) -> Self {
+        StateError {
+            msg: msg.to_string(),
+        }
+    }
+
+    fn new_with_read_only(file_path: &Path) -> Self {
+        Self::new(&format!("State file {:?} is read-only", file_path))
+    }
+
+    fn new_with_capacity(process: &ProcessConfig, max_running: usize) -> Self {
+        Self::new(&format!(
+            "Not starting {}, {} processes are running already (max_running)",
+            process, max_running
+        ))
+    }
+
+    fn new_with_dependency(process: &ProcessConfig, dependency: &str, reason: &str) -> Self {
+        Self::new(&format!(
+            "Not starting {}, dependency {:?} {}",
+            process, dependency, reason
+        ))
+    }
+
+    fn new_with_safe_mode(process: &ProcessConfig, pid: i32) -> Self {
+        Self::new(&format!(
+            "Not killing {} at {}, watchman didn't start it (--safe)",
+            process, pid
+        ))
+    }
+
+    fn new_with_not_ready(process: &ProcessConfig, timeout: Duration) -> Self {
+        let check = match process
+            .readiness_check
+            .as_ref()
+            .or(process.health_check.as_ref())
+        {
+            Some(check) => format!("{:?}", check),
+            None => format!("of port {}", process.port.unwrap_or_default()),
+        };
+        Self::new(&format!(
+            "{} didn't pass its readiness check {} within {:?}",
+            process, check, timeout
+        ))
+    }
+
+    fn new_with_signal(process: &ProcessConfig, signal: &str) -> Self {
+        Self::new(&format!("Unknown stop signal {:?} for {}", signal, process))
+    }
+
+    fn new_with_no_match(target: &str) -> Self {
+        Self::new(&format!("No process matches {:?}", target))
+    }
+
+    fn new_with_failures(action: &str, failed: &[String]) -> Self {
+        Self::new(&format!("Failed to {} {}", action, failed.join(", ")))
+    }
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl error::Error for StateError {}
+
+pub trait StateTrait {
     fn update_all(&mut self);
-    fn fix_all(&mut self) -> MayError;
-    fn from_file<P: AsRef<Path>>(file_path: P) -> Result<DS, ParseError>;
-    fn to_file<P: AsRef<Path>>(&self, file_path: P) -> MayError;
-    fn add(&mut self, cmd: String, name: Option<String>, output: Option<PathBuf>) -> MayError;
+    fn refresh_all(&mut self);
+    fn fix_all(
+        &mut self,
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<Vec<(String, RunOutcome)>, Box<dyn error::Error>>;
+    fn fix_selected(
+        &mut self,
+        indexes: &[usize],
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<Vec<(String, RunOutcome)>, Box<dyn error::Error>>;
+    fn restart_selected(
+        &mut self,
+        indexes: &[usize],
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<Vec<(String, RunOutcome)>, Box<dyn error::Error>>;
+    fn add(
+        &mut self,
+        process: ProcessConfig,
+        config: &Config,
+        wait: Option<Duration>,
+        persist: &mut Persist,
+    ) -> MayError;
+    fn run_one(
+        &mut self,
+        index: usize,
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<RunOutcome, Box<dyn error::Error>>;
+    fn run_one_with_cmd_override(
+        &mut self,
+        index: usize,
+        config: &Config,
+        cmd_override: Option<String>,
+        persist: &mut Persist,
+    ) -> Result<RunOutcome, Box<dyn error::Error>>;
+    fn matching(&self, target: &str) -> Result<Vec<usize>, StateError>;
+    fn start(
+        &mut self,
+        indexes: &[usize],
+        cmd_override: Option<String>,
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<Vec<(String, RunOutcome)>, Box<dyn error::Error>>;
+    fn stop(
+        &mut self,
+        indexes: &[usize],
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<StopReport, Box<dyn error::Error>>;
+    fn restart(
+        &mut self,
+        indexes: &[usize],
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<Vec<(String, RunOutcome)>, Box<dyn error::Error>>;
+    fn check_capacity(&mut self, index: usize, config: &Config) -> MayError;
+    fn check_dependencies(&mut self, index: usize) -> MayError;
+    fn restart_dependents(
+        &mut self,
+        index: usize,
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<Vec<String>, Box<dyn error::Error>>;
+    fn remove(&mut self, target: &str, config: &Config) -> Result<State, Box<dyn error::Error>>;
+    fn enabled_names(&self) -> Vec<String>;
+    fn leaked(&self) -> Vec<(String, system::Process)>;
+    fn solo(&mut self, target: &str, config: &Config, persist: &mut Persist) -> MayError;
+    fn restore(&mut self, enabled: &[String], config: &Config, persist: &mut Persist) -> MayError;
 }
 
 pub type State = Vec<ProcessConfig>;
 
 impl StateTrait for State {
-    fn add(&mut self, cmd: String, name: Option<String>, output: Option<PathBuf>) -> MayError {
-        let mut pc = ProcessConfig {
-            cmd,
-            name,
-            status: ProcessStatus::Disabled,
-            output,
+    /// Adds and starts a process. With `wait` it only counts as added once ready, otherwise
+    /// it's killed and left out of the state.
+    fn add(
+        &mut self,
+        process: ProcessConfig,
+        config: &Config,
+        wait: Option<Duration>,
+        persist: &mut Persist,
+    ) -> MayError {
+        if let Some(port) = process.port {
+            self.iter()
+                .filter(|other| other.port == Some(port))
+                .for_each(|other| eprintln!("Port {} is used by {} too", port, other));
+        }
+        self.push(process);
+
+        let index = self.len() - 1;
+        if let Err(err) = self.run_one(index, config, persist) {
+            self.pop();
+            return Result::Err(err);
+        }
+
+        if let Some(timeout) = wait {
+            if let Err(err) = self[index].wait_until_ready(timeout) {
+                eprintln!("Rolling back {}: {}", self[index], err);
+                if let Err(kill_err) = self[index].kill(config) {
+                    eprintln!("Failed to kill {}: {}", self[index], kill_err);
+                }
+                self.pop();
+                // The spawn was persisted already.
+                persist(self)?;
+                return Result::Err(err);
+            }
+        }
+
+        persist(self)
+    }
+
+    fn run_one(
+        &mut self,
+        index: usize,
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<RunOutcome, Box<dyn error::Error>> {
+        self.run_one_with_cmd_override(index, config, None, persist)
+    }
+
+    fn run_one_with_cmd_override(
+        &mut self,
+        index: usize,
+        config: &Config,
+        cmd_override: Option<String>,
+        persist: &mut Persist,
+    ) -> Result<RunOutcome, Box<dyn error::Error>> {
+        // Taken before the checks, they may adopt already.
+        let before = self[index].status.clone();
+        self.check_capacity(index, config)?;
+        self.check_dependencies(index)?;
+
+        let spawned = self[index].run(config, cmd_override)?;
+        let status = self[index].status.clone();
+
+        // A changed status means a fresh spawn (or an adoption), persist it right away.
+        if status != before {
+            persist(self)?;
+        }
+
+        let pid = self[index].get_pid().unwrap_or_default();
+        Result::Ok(if spawned {
+            RunOutcome::Started(pid)
+        } else if status == before {
+            RunOutcome::AlreadyRunning(pid)
+        } else {
+            RunOutcome::Adopted(pid)
+        })
+    }
+
+    /// Processes `target` refers to: by name (or cmd for unnamed ones), otherwise by position
+    /// in `watchman show`, counting from 1.
+    fn matching(&self, target: &str) -> Result<Vec<usize>, StateError> {
+        let indexes: Vec<usize> = (0..self.len())
+            .filter(|index| self[*index].matches(target))
+            .collect();
+        if !indexes.is_empty() {
+            return Result::Ok(indexes);
+        }
+
+        match target.parse::<usize>() {
+            Ok(position) if position >= 1 && position <= self.len() => {
+                Result::Ok(vec![position - 1])
+            }
+            _ => Result::Err(StateError::new_with_no_match(target)),
+        }
+    }
+
+    /// Starts the processes at `indexes`. With `cmd_override` a running instance is
+    /// replaced by one running that command instead, just this once.
+    fn start(
+        &mut self,
+        indexes: &[usize],
+        cmd_override: Option<String>,
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<Vec<(String, RunOutcome)>, Box<dyn error::Error>> {
+        let mut report = vec![];
+        for index in indexes {
+            if cmd_override.is_some() {
+                self[*index].kill(config)?;
+            }
+            let outcome =
+                self.run_one_with_cmd_override(*index, config, cmd_override.clone(), persist)?;
+            report.push((self[*index].to_string(), outcome));
+        }
+
+        Result::Ok(report)
+    }
+
+    /// Stops and disables the processes at `indexes`. One that fails doesn't keep the rest
+    /// running, the state is persisted either way.
+    fn stop(
+        &mut self,
+        indexes: &[usize],
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<StopReport, Box<dyn error::Error>> {
+        let mut report = vec![];
+        let mut failed = vec![];
+        for index in indexes {
+            match self[*index].kill(config) {
+                Ok(outcome) => report.push((self[*index].to_string(), outcome)),
+                Err(err) => {
+                    eprintln!("Stopping {} FAILED with {}", self[*index], err);
+                    failed.push(self[*index].to_string());
+                }
+            }
+        }
+
+        persist(self)?;
+        if !failed.is_empty() {
+            return Result::Err(Box::new(StateError::new_with_failures("stop", &failed)));
+        }
+        Result::Ok(report)
+    }
+
+    /// Stops the processes at `indexes` and starts them again, disabled ones are just started.
+    /// Like `stop` it goes through all of them and persists the state before erroring.
+    fn restart(
+        &mut self,
+        indexes: &[usize],
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<Vec<(String, RunOutcome)>, Box<dyn error::Error>> {
+        let mut report = vec![];
+        let mut failed = vec![];
+        for index in indexes {
+            let outcome = self[*index]
+                .kill_for_restart(config)
+                .and_then(|_| self.run_one(*index, config, persist));
+            match outcome {
+                Ok(outcome) => report.push((self[*index].to_string(), outcome)),
+                Err(err) => {
+                    eprintln!("Restarting {} FAILED with {}", self[*index], err);
+                    failed.push(self[*index].to_string());
+                }
+            }
+        }
+
+        persist(self)?;
+        if !failed.is_empty() {
+            return Result::Err(Box::new(StateError::new_with_failures("restart", &failed)));
+        }
+        Result::Ok(report)
+    }
+
+    /// Errors unless every process in `depends_on` is running and ready.
+    fn check_dependencies(&mut self, index: usize) -> MayError {
+        if self[index].depends_on.is_empty() {
+            return Result::Ok(());
+        }
+
+        // Already running processes are not held back.
+        self[index].update();
+        if self[index].is_running() {
+            return Result::Ok(());
+        }
+
+        for dependency in self[index].depends_on.clone() {
+            let reason = match self.iter_mut().find(|process| process.matches(&dependency)) {
+                None => "doesn't exist",
+                Some(process) => {
+                    process.update();
+                    if !process.is_running() {
+                        "is not running"
+                    } else if !process.is_ready() {
+                        "is not ready"
+                    } else {
+                        continue;
+                    }
+                }
+            };
+            return Result::Err(Box::new(StateError::new_with_dependency(
+                &self[index],
+                &dependency,
+                reason,
+            )));
+        }
+
+        Result::Ok(())
+    }
+
+    /// Kills and removes every process matching `target`, returning the removed ones.
+    ///
+    /// Several entries can share a cmd, all of them are removed. Matching nothing is an error.
+    fn remove(&mut self, target: &str, config: &Config) -> Result<State, Box<dyn error::Error>> {
+        // Refuse before anything is removed, not halfway through.
+        for process in self.iter_mut().filter(|process| process.matches(target)) {
+            process.update();
+            process.check_killable(config)?;
+        }
+
+        let (mut removed, kept): (State, State) =
+            self.drain(..).partition(|process| process.matches(target));
+        *self = kept;
+
+        if removed.is_empty() {
+            return Result::Err(Box::new(StateError::new_with_no_match(target)));
+        }
+
+        for process in removed.iter_mut() {
+            process.kill(config)?;
+        }
+
+        Result::Ok(removed)
+    }
+
+    /// Fails if starting the process would go over `max_running`.
+    fn check_capacity(&mut self, index: usize, config: &Config) -> MayError {
+        let max_running = match config.max_running {
+            Some(max_running) if !self[index].critical => max_running,
+            _ => return Result::Ok(()),
         };
 
-        pc.run()?;
+        // Already running (or adoptable) processes don't take another slot.
+        self[index].update();
+        if self[index].is_running() {
+            return Result::Ok(());
+        }
+
+        let running = self.iter().filter(|process| process.is_running()).count();
+        if running >= max_running {
+            return Result::Err(Box::new(StateError::new_with_capacity(
+                &self[index],
+                max_running,
+            )));
+        }
+
+        Result::Ok(())
+    }
+
+    /// Restarts the enabled processes that depend on the one at `index` and asked to be
+    /// restarted with it, transitively, dependencies before their dependents.
+    fn restart_dependents(
+        &mut self,
+        index: usize,
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<Vec<String>, Box<dyn error::Error>> {
+        let mut queue = vec![self[index].to_string()];
+        let mut order: Vec<usize> = vec![];
+
+        while !queue.is_empty() {
+            let dependency = queue.remove(0);
+            for (i, process) in self.iter().enumerate() {
+                if i == index || order.contains(&i) {
+                    continue;
+                }
+                if process.restart_dependents_on_dependency_restart
+                    && process.is_enabled()
+                    && process.depends_on.contains(&dependency)
+                {
+                    order.push(i);
+                    queue.push(process.to_string());
+                }
+            }
+        }
+
+        let mut restarted = vec![];
+        for i in order {
+            // Stays enabled, if it can't be started right now `watch` picks it up later.
+            self[i].kill_for_restart(config)?;
+            self.run_one(i, config, persist)?;
+            restarted.push(self[i].to_string());
+        }
+
+        Result::Ok(restarted)
+    }
+
+    /// Processes running a configured command that aren't any process's recorded pid,
+    /// usually left behind by an earlier session. Statuses should be refreshed first.
+    fn leaked(&self) -> Vec<(String, system::Process)> {
+        let tracked: Vec<i32> = self
+            .iter()
+            .filter(|process| process.is_running())
+            .filter_map(|process| process.get_pid())
+            .collect();
+        let own_pid = std::process::id() as i32;
+
+        let mut leaked: Vec<(String, system::Process)> = vec![];
+        for process in self.iter() {
+            for found in system::get_all_by_cmd(&process.effective_cmd()) {
+                let known = tracked.contains(&found.pid)
+                    || found.pid == own_pid
+                    || leaked.iter().any(|(_, other)| other.pid == found.pid);
+                if !known {
+                    leaked.push((process.to_string(), found));
+                }
+            }
+        }
+
+        leaked
+    }
 
-        self.push(pc);
+    fn enabled_names(&self) -> Vec<String> {
+        self.iter()
+            .filter(|process| process.is_enabled())
+            .map(|process| process.to_string())
+            .collect()
+    }
+
+    fn solo(&mut self, target: &str, config: &Config, persist: &mut Persist) -> MayError {
+        if !self.iter().any(|process| process.matches(target)) {
+            return Result::Err(Box::new(StateError::new_with_no_match(target)));
+        }
+
+        for index in 0..self.len() {
+            if self[index].matches(target) {
+                self.run_one(index, config, persist)?;
+            } else if self[index].is_enabled() {
+                println!("Disabling {}", self[index]);
+                self[index].kill(config)?;
+            }
+        }
+
+        Result::Ok(())
+    }
+
+    fn restore(&mut self, enabled: &[String], config: &Config, persist: &mut Persist) -> MayError {
+        for index in 0..self.len() {
+            let wanted = enabled.contains(&self[index].to_string());
+            if wanted {
+                self.run_one(index, config, persist)?;
+            } else if self[index].is_enabled() {
+                println!("Disabling {}", self[index]);
+                self[index].kill(config)?;
+            }
+        }
 
         Result::Ok(())
     }
@@ -201,23 +1343,175 @@ impl StateTrait for State {
         self.iter_mut().for_each(|process| process.update());
     }
 
-    fn fix_all(&mut self) -> MayError {
-        self.iter_mut()
-            .map(|process| process.fix())
-            .collect::<MayError>()?;
+    fn refresh_all(&mut self) {
+        self.iter_mut().for_each(|process| process.refresh());
+    }
 
-        Result::Ok(())
+    /// Starts every enabled process that isn't running, returning what happened to each.
+    fn fix_all(
+        &mut self,
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<Vec<(String, RunOutcome)>, Box<dyn error::Error>> {
+        let indexes: Vec<usize> = (0..self.len()).collect();
+        self.fix_selected(&indexes, config, persist)
+    }
+
+    /// Restarts the enabled processes among `indexes`, running or not.
+    fn restart_selected(
+        &mut self,
+        indexes: &[usize],
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<Vec<(String, RunOutcome)>, Box<dyn error::Error>> {
+        for index in indexes {
+            if self[*index].is_enabled() {
+                self[*index].kill_for_restart(config)?;
+            }
+        }
+        self.fix_selected(indexes, config, persist)
     }
 
-    fn from_file<P: AsRef<Path>>(file_path: P) -> Result<Self, ParseError> {
+    /// Like `fix_all`, limited to the processes at `indexes`.
+    fn fix_selected(
+        &mut self,
+        indexes: &[usize],
+        config: &Config,
+        persist: &mut Persist,
+    ) -> Result<Vec<(String, RunOutcome)>, Box<dyn error::Error>> {
+        let mut report = vec![];
+        // The checks below adopt already, compare against how things were before them.
+        let initial: Vec<ProcessStatus> =
+            self.iter().map(|process| process.status.clone()).collect();
+        let mut pending: Vec<usize> = indexes
+            .iter()
+            .copied()
+            .filter(|index| self[*index].is_enabled())
+            .collect();
+
+        // Processes waiting for a dependency get another go once something else has started.
+        loop {
+            let mut waiting = vec![];
+            let mut started = false;
+            for index in pending {
+                if let Err(err) = self.check_capacity(index, config) {
+                    eprintln!("{}", err);
+                    continue;
+                }
+                if let Err(err) = self.check_dependencies(index) {
+                    waiting.push((index, err));
+                    continue;
+                }
+                let outcome = match self.run_one(index, config, persist)? {
+                    RunOutcome::AlreadyRunning(pid)
+                        if initial[index].running_pid() != Some(pid) =>
+                    {
+                        RunOutcome::Adopted(pid)
+                    }
+                    outcome => outcome,
+                };
+                report.push((self[index].to_string(), outcome));
+                started = true;
+            }
+
+            if !started || waiting.is_empty() {
+                waiting.iter().for_each(|(_, err)| eprintln!("{}", err));
+                break;
+            }
+            pending = waiting.into_iter().map(|(index, _)| index).collect();
+        }
+
+        Result::Ok(report)
+    }
+}
+
+/// Everything kept in the state file.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct StateFile {
+    #[serde(default)]
+    pub config: Config,
+    #[serde(default)]
+    pub processes: State,
+    /// Processes that were enabled before `watchman solo`, restored by `watchman unsolo`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub solo: Option<Vec<String>>,
+    /// Id of the current supervisor session, see `inject_run_id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    /// Named lists of one-shot shell commands, run in order by `watchman task`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tasks: BTreeMap<String, Vec<String>>,
+}
+
+/// State files used to be a bare list of processes, those are still read.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StateFileFormat {
+    Legacy(State),
+    Current(Box<StateFile>),
+}
+
+impl StateFile {
+    /// Reads a state file, one that doesn't exist yet is empty and created once written.
+    pub fn from_file<P: AsRef<Path>>(file_path: P) -> Result<Self, ParseError> {
+        if !file_path.as_ref().exists() {
+            return Result::Ok(StateFile::default());
+        }
         let contents =
             fs::read_to_string(file_path).expect("Something went wrong reading the file");
 
-        serde_json::from_str(&contents)
+        Result::Ok(match serde_json::from_str(&contents)? {
+            StateFileFormat::Legacy(processes) => StateFile {
+                processes,
+                ..Default::default()
+            },
+            StateFileFormat::Current(file) => *file,
+        })
+    }
+
+    /// Layers the processes of `layer` on top of these. A process defined in both is
+    /// replaced by `layer`'s definition as a whole, keeping the status and other runtime
+    /// fields, others are added at the end. Only processes are layered, `config` stays.
+    pub fn merge(&mut self, layer: StateFile) {
+        for process in layer.processes {
+            match self
+                .processes
+                .iter_mut()
+                .find(|existing| existing.is_same_process(&process))
+            {
+                Some(existing) => *existing = process.with_runtime_of(existing),
+                None => self.processes.push(process),
+            }
+        }
+    }
+
+    /// Writes this file's sections with `processes` in place of its own.
+    pub fn to_file_with<P: AsRef<Path>>(&self, file_path: P, processes: &State) -> MayError {
+        StateFile {
+            processes: processes.clone(),
+            ..self.clone()
+        }
+        .to_file(file_path)
     }
 
-    fn to_file<P: AsRef<Path>>(&self, file_path: P) -> MayError {
-        let mut buffer = File::create(file_path)?;
+    /// Fails with a clear message up front instead of halfway through a command.
+    pub fn check_writable<P: AsRef<Path>>(file_path: P) -> MayError {
+        match OpenOptions::new().append(true).open(&file_path) {
+            Err(ref err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                Result::Err(Box::new(StateError::new_with_read_only(file_path.as_ref())))
+            }
+            _ => Result::Ok(()),
+        }
+    }
+
+    pub fn to_file<P: AsRef<Path>>(&self, file_path: P) -> MayError {
+        let mut buffer = File::create(&file_path).map_err(|err| -> Box<dyn error::Error> {
+            if err.kind() == io::ErrorKind::PermissionDenied {
+                Box::new(StateError::new_with_read_only(file_path.as_ref()))
+            } else {
+                Box::new(err)
+            }
+        })?;
 
         let serialized = serde_json::to_string_pretty(&self)?;
 
@@ -227,3 +1521,304 @@ impl StateTrait for State {
         std::result::Result::Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::ScratchDir;
+    use std::process::Command;
+
+    fn sleeper(dir: &ScratchDir, cmd: &str) -> ProcessConfig {
+        ProcessConfig {
+            output: Some(dir.path().join("sleeper.log")),
+            ..ProcessConfig::new(cmd.to_string(), Some("sleeper".to_string()))
+        }
+    }
+
+    /// An enabled process named `name` that isn't running, the pid is beyond any real one.
+    fn enabled(dir: &ScratchDir, name: &str, cmd: &str) -> ProcessConfig {
+        ProcessConfig {
+            status: ProcessStatus::Stopped(i32::MAX),
+            output: Some(dir.path().join(format!("{}.log", name))),
+            ..ProcessConfig::new(cmd.to_string(), Some(name.to_string()))
+        }
+    }
+
+    fn no_persist(_: &State) -> MayError {
+        Result::Ok(())
+    }
+
+    fn running(state: &State) -> Vec<String> {
+        state
+            .iter()
+            .filter(|process| process.is_running())
+            .map(|process| process.to_string())
+            .collect()
+    }
+
+    fn stop_all(state: &mut State) {
+        for process in state.iter_mut() {
+            process.kill(&Config::default()).unwrap();
+        }
+    }
+
+    #[test]
+    fn solo_disables_the_rest_and_restore_brings_them_back() {
+        let dir = ScratchDir::new("solo");
+        let config = Config::default();
+        let mut state = vec![
+            enabled(&dir, "a", "sleep 7751"),
+            enabled(&dir, "b", "sleep 7752"),
+        ];
+        state.fix_all(&config, &mut no_persist).unwrap();
+        let before = state.enabled_names();
+
+        state.solo("a", &config, &mut no_persist).unwrap();
+        assert_eq!(running(&state), vec!["a"]);
+        assert!(!state[1].is_enabled());
+
+        state.restore(&before, &config, &mut no_persist).unwrap();
+        assert_eq!(running(&state), vec!["a", "b"]);
+        assert!(state.solo("c", &config, &mut no_persist).is_err());
+
+        stop_all(&mut state);
+    }
+
+    #[test]
+    fn safe_stop_leaves_another_instance_of_the_command_alone() {
+        let dir = ScratchDir::new("safe-stop");
+        let config = Config {
+            safe: true,
+            ..Default::default()
+        };
+        let mut process = sleeper(&dir, "sleep 7731");
+        assert!(process.run(&config, None).unwrap());
+        let spawned = process.get_pid().unwrap();
+        let mut foreign = Command::new("sleep").arg("7731").spawn().unwrap();
+
+        assert_eq!(process.kill(&config).unwrap(), Some(StopOutcome::Exited));
+        assert_eq!(system::reap(spawned), Some(128 + libc::SIGTERM));
+        assert_eq!(process.status, ProcessStatus::Disabled);
+        assert!(!process.adopted);
+        assert!(foreign.try_wait().unwrap().is_none());
+
+        foreign.kill().unwrap();
+        foreign.wait().unwrap();
+    }
+
+    #[test]
+    fn stop_of_a_dead_process_adopts_nothing() {
+        let dir = ScratchDir::new("dead-stop");
+        let config = Config::default();
+        let mut process = sleeper(&dir, "sleep 7732");
+        assert!(process.run(&config, None).unwrap());
+        let spawned = process.get_pid().unwrap();
+        system::kill_by_pid(spawned);
+        while system::reap(spawned).is_none() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let mut foreign = Command::new("sleep").arg("7732").spawn().unwrap();
+
+        assert_eq!(process.kill(&config).unwrap(), None);
+        assert_eq!(process.status, ProcessStatus::Disabled);
+        assert!(!process.adopted);
+        assert!(foreign.try_wait().unwrap().is_none());
+
+        foreign.kill().unwrap();
+        foreign.wait().unwrap();
+    }
+
+    #[test]
+    fn max_running_holds_back_all_but_critical_processes() {
+        let dir = ScratchDir::new("max-running");
+        let config = Config {
+            max_running: Some(1),
+            ..Default::default()
+        };
+        let mut state = vec![
+            enabled(&dir, "a", "sleep 7761"),
+            enabled(&dir, "b", "sleep 7762"),
+            ProcessConfig {
+                critical: true,
+                ..enabled(&dir, "c", "sleep 7763")
+            },
+        ];
+
+        let report = state.fix_all(&config, &mut no_persist).unwrap();
+        let started: Vec<&str> = report.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(started, vec!["a", "c"]);
+        assert_eq!(running(&state), vec!["a", "c"]);
+        assert!(state[1].is_enabled());
+
+        stop_all(&mut state);
+    }
+
+    #[test]
+    fn dependencies_start_first_and_restart_their_dependents() {
+        let dir = ScratchDir::new("depends-on");
+        let config = Config::default();
+        let mut state = vec![
+            ProcessConfig {
+                depends_on: vec!["a".to_string()],
+                restart_dependents_on_dependency_restart: true,
+                ..enabled(&dir, "b", "sleep 7771")
+            },
+            enabled(&dir, "a", "sleep 7772"),
+            ProcessConfig {
+                depends_on: vec!["missing".to_string()],
+                ..enabled(&dir, "c", "sleep 7773")
+            },
+        ];
+
+        let report = state.fix_all(&config, &mut no_persist).unwrap();
+        let started: Vec<&str> = report.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(started, vec!["a", "b"]);
+        assert_eq!(running(&state), vec!["b", "a"]);
+
+        let before = state[0].get_pid();
+        let restarted = state
+            .restart_dependents(1, &config, &mut no_persist)
+            .unwrap();
+        assert_eq!(restarted, vec!["b"]);
+        assert!(state[0].is_running());
+        assert_ne!(state[0].get_pid(), before);
+
+        stop_all(&mut state);
+    }
+
+    #[test]
+    fn run_adopts_by_command_and_safe_mode_refuses_to_stop_it() {
+        let dir = ScratchDir::new("adopt");
+        let mut foreign = Command::new("sleep").arg("7781").spawn().unwrap();
+        let mut process = enabled(&dir, "a", "sleep 7781");
+
+        assert!(!process.run(&Config::default(), None).unwrap());
+        assert_eq!(process.status.running_pid(), Some(foreign.id() as i32));
+        assert!(process.adopted);
+
+        let safe = Config {
+            safe: true,
+            ..Default::default()
+        };
+        assert!(process.kill(&safe).is_err());
+        assert!(process.is_running());
+        assert!(foreign.try_wait().unwrap().is_none());
+
+        foreign.kill().unwrap();
+        foreign.wait().unwrap();
+    }
+
+    #[test]
+    fn merge_replaces_definitions_but_keeps_runtime_fields() {
+        let process =
+            |name: &str, cmd: &str| ProcessConfig::new(cmd.to_string(), Some(name.to_string()));
+        let mut base = StateFile {
+            processes: vec![
+                ProcessConfig {
+                    status: ProcessStatus::Running(5, None),
+                    restarts: 2,
+                    env: vec![("A".to_string(), "1".to_string())]
+                        .into_iter()
+                        .collect(),
+                    ..process("a", "sleep 1")
+                },
+                process("b", "sleep 2"),
+            ],
+            ..Default::default()
+        };
+        let layer = StateFile {
+            config: Config {
+                stop_timeout: Some(1),
+                ..Default::default()
+            },
+            processes: vec![
+                ProcessConfig {
+                    port: Some(80),
+                    ..process("a", "sleep 3")
+                },
+                process("c", "sleep 4"),
+            ],
+            ..Default::default()
+        };
+
+        base.merge(layer);
+        let names: Vec<String> = base.processes.iter().map(|p| p.to_string()).collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+        let merged = &base.processes[0];
+        assert_eq!(merged.cmd, "sleep 3");
+        assert_eq!(merged.port, Some(80));
+        assert!(merged.env.is_empty());
+        assert_eq!(merged.status, ProcessStatus::Running(5, None));
+        assert_eq!(merged.restarts, 2);
+        assert_eq!(base.config.stop_timeout, None);
+    }
+
+    #[test]
+    fn stop_takes_children_of_a_shell_along() {
+        let dir = ScratchDir::new("group-stop");
+        let config = Config::default();
+        let mut process = ProcessConfig {
+            args: vec!["-c".to_string(), "sleep 7791; true".to_string()],
+            ..enabled(&dir, "sh", "sh")
+        };
+        assert!(process.run(&config, None).unwrap());
+        let child = loop {
+            if let Some(child) = system::get_by_cmd(&"sleep 7791".to_string()) {
+                break child;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        process.kill(&config).unwrap();
+        // The shell may be gone a moment before its child is.
+        let stopped = Instant::now();
+        while system::get_by_pid(child.pid).is_some() {
+            assert!(stopped.elapsed() < Duration::from_secs(1), "{:?}", child);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn statuses_of_older_state_files_are_read() {
+        let status = |json: &str| serde_json::from_str::<ProcessStatus>(json).unwrap();
+        let identity = Identity {
+            start_time: 7,
+            uid: 1000,
+        };
+
+        assert_eq!(
+            status(r#"{"Running":1234}"#),
+            ProcessStatus::Running(1234, None)
+        );
+        assert_eq!(
+            status(r#"{"Running":[1234,{"start_time":7,"uid":1000}]}"#),
+            ProcessStatus::Running(1234, Some(identity))
+        );
+        assert_eq!(status(r#""Disabled""#), ProcessStatus::Disabled);
+        assert_eq!(status(r#"{"Stopped":5}"#), ProcessStatus::Stopped(5));
+
+        let legacy: State =
+            serde_json::from_str(r#"[{"name":"a","cmd":"sleep 1","status":{"Running":5}}]"#)
+                .unwrap();
+        assert_eq!(legacy[0].status, ProcessStatus::Running(5, None));
+        assert!(legacy[0].adopted);
+    }
+
+    #[test]
+    fn a_reused_pid_is_invalid() {
+        let own = system::get_by_pid(std::process::id() as i32).unwrap();
+        let mut process = ProcessConfig {
+            status: ProcessStatus::Running(
+                own.pid,
+                Some(Identity {
+                    start_time: own.start_time + 1,
+                    uid: own.uid,
+                }),
+            ),
+            ..ProcessConfig::new(own.cmd.clone(), None)
+        };
+
+        process.refresh();
+        assert_eq!(process.status, ProcessStatus::Invalid(own.pid));
+    }
+}
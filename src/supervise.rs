@@ -0,0 +1,737 @@
+use rand::Rng;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::config::MAX_LOG_SIZE_KEEP;
+use crate::events::Events;
+use crate::state::MayError;
+use crate::state::Persist;
+use crate::state::ProcessConfig;
+use crate::state::ProcessStatus;
+use crate::state::RestartPolicy;
+use crate::state::RunOutcome;
+use crate::state::State;
+use crate::state::StateError;
+use crate::state::StateTrait;
+use crate::system;
+use crate::system::StopOutcome;
+use crate::utils;
+
+/// Set by SIGUSR2, see `watchman upgrade`.
+static UPGRADE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set for the re-executed watchman, so it carries on with the same session.
+pub const UPGRADE_VAR: &str = "WATCHMAN_UPGRADE";
+
+/// How often a sleeping watch loop checks whether it was asked to upgrade.
+const SIGNAL_POLL: Duration = Duration::from_millis(100);
+
+extern "C" fn request_upgrade(_signal: i32) {
+    UPGRADE_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Set by the signals asking watchman to exit: SIGINT, SIGTERM and SIGHUP.
+static EXIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_exit(_signal: i32) {
+    EXIT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn on_exit_signals() {
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP].iter() {
+        system::on_signal(*signal, request_exit);
+    }
+}
+
+/// Stays attached to the processes at `spawned` until they have all exited or watchman is
+/// asked to exit, stopping the ones still running then.
+pub fn attach(
+    state: &mut State,
+    spawned: &[usize],
+    config: &Config,
+    persist: &mut Persist,
+) -> MayError {
+    on_exit_signals();
+    println!("Attached, stopping the started processes on exit");
+
+    while !EXIT_REQUESTED.load(Ordering::SeqCst) {
+        thread::sleep(SIGNAL_POLL);
+        state.refresh_all();
+        if !spawned.iter().any(|index| state[*index].is_running()) {
+            return persist(state);
+        }
+    }
+
+    for index in spawned {
+        println!("Stopping {}", state[*index]);
+        match state[*index].kill(config) {
+            Ok(Some(StopOutcome::Killed)) => println!("Killed {}", state[*index]),
+            Ok(_) => {}
+            Err(err) => eprintln!("Stopping {} FAILED with {}", state[*index], err),
+        }
+    }
+    persist(state)
+}
+
+/// Replaces this watchman with whatever binary is now at the path it was started from,
+/// same arguments and same pid. Children keep running and stay ours, the new binary
+/// picks them up from the state file.
+fn exec_self() -> Box<dyn error::Error> {
+    let mut args = std::env::args_os();
+    let program = match args.next() {
+        Some(program) => program,
+        None => return Box::new(std::io::Error::other("Unknown path of watchman")),
+    };
+    Box::new(
+        Command::new(program)
+            .args(args)
+            .env(UPGRADE_VAR, "1")
+            .exec(),
+    )
+}
+
+/// Sleeps for `interval`, calling `on_poll` every `SIGNAL_POLL` meanwhile. Cut short when
+/// `on_poll` returns true.
+fn sleep_unless_signalled(interval: Duration, mut on_poll: impl FnMut() -> bool) {
+    let started = Instant::now();
+    let signalled =
+        || UPGRADE_REQUESTED.load(Ordering::SeqCst) || EXIT_REQUESTED.load(Ordering::SeqCst);
+    while started.elapsed() < interval && !signalled() {
+        if on_poll() {
+            return;
+        }
+        thread::sleep(SIGNAL_POLL.min(interval.saturating_sub(started.elapsed())));
+    }
+}
+
+/// What a stop running on its own thread ended with.
+struct Stopped {
+    key: String,
+    for_restart: bool,
+    status: ProcessStatus,
+    adopted: bool,
+    outcome: Option<StopOutcome>,
+    error: Option<String>,
+}
+
+/// Stops running on their own threads, so a process taking its whole `stop_timeout` doesn't
+/// hold up supervising the others. Only the watch loop touches the state: threads stop a copy
+/// of the process and send back how it went.
+struct Stops {
+    sender: Sender<Stopped>,
+    receiver: Receiver<Stopped>,
+    pending: HashSet<String>,
+}
+
+impl Stops {
+    fn new() -> Self {
+        let (sender, receiver) = channel();
+        Stops {
+            sender,
+            receiver,
+            pending: HashSet::new(),
+        }
+    }
+
+    fn key(process: &ProcessConfig) -> String {
+        format!("{:+}", process)
+    }
+
+    fn is_stopping(&self, process: &ProcessConfig) -> bool {
+        self.pending.contains(&Self::key(process))
+    }
+
+    /// Stops `process` in the background, with `for_restart` it stays enabled.
+    fn spawn(&mut self, process: &ProcessConfig, config: &Config, for_restart: bool) {
+        let key = Self::key(process);
+        if !self.pending.insert(key.clone()) {
+            return;
+        }
+
+        let mut process = process.clone();
+        let config = config.clone();
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let result = if for_restart {
+                process.kill_for_restart(&config)
+            } else {
+                process.kill(&config)
+            };
+            let (outcome, error) = match result {
+                Ok(outcome) => (outcome, None),
+                Err(err) => (None, Some(err.to_string())),
+            };
+            let _ = sender.send(Stopped {
+                key,
+                for_restart,
+                status: process.status,
+                adopted: process.adopted,
+                outcome,
+                error,
+            });
+        });
+    }
+
+    /// Applies the stops that have finished, returns how many there were.
+    fn collect(&mut self, state: &mut State, events: &Events) -> usize {
+        let finished: Vec<Stopped> = self.receiver.try_iter().collect();
+        let count = finished.len();
+        finished
+            .into_iter()
+            .for_each(|stopped| self.apply(stopped, state, events));
+        count
+    }
+
+    /// Blocks until every pending stop has finished.
+    fn wait(&mut self, state: &mut State, events: &Events) {
+        while !self.pending.is_empty() {
+            match self.receiver.recv() {
+                Ok(stopped) => self.apply(stopped, state, events),
+                Err(_) => return,
+            }
+        }
+    }
+
+    fn apply(&mut self, stopped: Stopped, state: &mut State, events: &Events) {
+        self.pending.remove(&stopped.key);
+        let process = match state
+            .iter_mut()
+            .find(|process| Self::key(process) == stopped.key)
+        {
+            Some(process) => process,
+            None => return,
+        };
+
+        if let Some(error) = stopped.error {
+            events.emit(
+                "failed",
+                Some(&process.to_string()),
+                json!({ "error": error }),
+            );
+            return;
+        }
+        process.status = stopped.status;
+        process.adopted = stopped.adopted;
+        if !stopped.for_restart {
+            // Only orphans are stopped for good, their parent pid could be reused by now.
+            process.parent_pid = None;
+        }
+        events.emit(
+            "stopped",
+            Some(&process.to_string()),
+            json!({
+                "status": process.status,
+                "outcome": stopped.outcome.map(|outcome| outcome.to_string()),
+            }),
+        );
+    }
+}
+
+/// Asks the `watchman watch` that recorded its pid at `pid_path` to re-exec itself.
+pub fn upgrade(pid_path: &Path) -> Result<i32, Box<dyn error::Error>> {
+    let pid: i32 = fs::read_to_string(pid_path)
+        .map_err(|err| format!("No watchman watch found at {:?}: {}", pid_path, err))?
+        .trim()
+        .parse()?;
+    if system::get_by_pid(pid).is_none() {
+        return Err(format!("watchman watch at {} isn't running ({:?})", pid, pid_path).into());
+    }
+
+    system::send_signal(pid, libc::SIGUSR2)?;
+    Ok(pid)
+}
+
+fn run_flight(name: &str, cmd: &str) -> MayError {
+    system::run_shell_checked(cmd).map_err(|error| -> Box<dyn error::Error> {
+        Box::new(StateError::new(&format!("{} {:?} {}", name, cmd, error)))
+    })
+}
+
+/// Runs `config.pre_flight`, an error means processes shouldn't be started.
+pub fn pre_flight(config: &Config) -> MayError {
+    match config.pre_flight {
+        Some(ref cmd) => run_flight("pre_flight", cmd),
+        None => Ok(()),
+    }
+}
+
+/// Runs `config.post_flight`, for when everything has been stopped.
+pub fn post_flight(config: &Config) -> MayError {
+    match config.post_flight {
+        Some(ref cmd) => run_flight("post_flight", cmd),
+        None => Ok(()),
+    }
+}
+
+/// How long an `on_restart` hook may run before it's killed.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest `restart_backoff` gets, however often it's doubled.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How long a restarted process has to stay up for its retries to start over, unless its
+/// backoff is longer. Otherwise one crashing a bit slower than the interval never runs out.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// What restart policies need to carry over between passes. Kept in memory only, a
+/// restarted watch starts over without backoff.
+struct Retries {
+    /// Earliest restart of processes backing off.
+    next_at: HashMap<String, Instant>,
+    /// Processes stopped for failing their liveness check, a failure whatever they exit with.
+    unhealthy: HashSet<String>,
+    /// When restarted processes that are still up count as recovered.
+    stable_at: HashMap<String, Instant>,
+}
+
+impl Retries {
+    fn new() -> Self {
+        Retries {
+            next_at: HashMap::new(),
+            unhealthy: HashSet::new(),
+            stable_at: HashMap::new(),
+        }
+    }
+
+    /// Processes that have kept running since their last restart have recovered, their
+    /// retries start over.
+    fn reset_running(&mut self, state: &mut State) {
+        let now = Instant::now();
+        for process in state.iter_mut() {
+            if !process.is_running() || process.retries == 0 {
+                continue;
+            }
+            let key = Stops::key(process);
+            // Retries left over from an earlier watch count from the first time it's seen.
+            let stable_at = *self
+                .stable_at
+                .entry(key.clone())
+                .or_insert(now + STABLE_AFTER);
+            if now >= stable_at {
+                process.retries = 0;
+                self.next_at.remove(&key);
+                self.stable_at.remove(&key);
+            }
+        }
+    }
+
+    /// Counts a restart of `process`, holding off the next one by its backoff.
+    fn record(&mut self, process: &mut ProcessConfig) {
+        process.retries += 1;
+        let now = Instant::now();
+        let mut stable_after = STABLE_AFTER;
+        if let Some(backoff) = process.restart_backoff {
+            let factor = 2u64.saturating_pow(process.retries - 1);
+            let delay = Duration::from_secs(backoff.saturating_mul(factor)).min(MAX_BACKOFF);
+            self.next_at.insert(Stops::key(process), now + delay);
+            stable_after = stable_after.max(delay);
+        }
+        self.stable_at
+            .insert(Stops::key(process), now + stable_after);
+    }
+
+    fn backing_off(&self, process: &ProcessConfig) -> Option<Duration> {
+        let next_at = self.next_at.get(&Stops::key(process))?;
+        next_at.checked_duration_since(Instant::now())
+    }
+}
+
+/// Hook failures are reported but never stand in the way of the restart.
+fn run_restart_hook(
+    hook: &str,
+    name: &str,
+    exit_code: Option<i32>,
+    restarts: u32,
+    events: &Events,
+) {
+    let exit_code = exit_code.map_or("unknown".to_string(), |code| code.to_string());
+    let cmd = format!("{} {} {}", hook, exit_code, restarts);
+
+    let error = match system::run_shell_with_timeout(&cmd, HOOK_TIMEOUT) {
+        Ok(Some(status)) if status.success() => return,
+        Ok(Some(status)) => format!("exited with {:?}", status.code()),
+        Ok(None) => format!("timed out after {:?}", HOOK_TIMEOUT),
+        Err(err) => err.to_string(),
+    };
+    events.emit(
+        "hook_failed",
+        Some(name),
+        json!({ "hook": hook, "error": error }),
+    );
+}
+
+/// Stops processes bound to a parent pid that has gone away.
+fn stop_orphans(state: &mut State, config: &Config, events: &Events, stops: &mut Stops) {
+    for process in state.iter() {
+        let parent_pid = match process.parent_pid {
+            Some(pid) if process.is_enabled() && !stops.is_stopping(process) => pid,
+            _ => continue,
+        };
+        if system::get_by_pid(parent_pid).is_some() {
+            continue;
+        }
+
+        events.emit(
+            "parent_gone",
+            Some(&process.to_string()),
+            json!({ "parent_pid": parent_pid, "status": process.status }),
+        );
+        stops.spawn(process, config, false);
+    }
+}
+
+/// Kills running processes whose liveness check fails, a later reconcile pass restarts them.
+/// Ones that are never restarted are only reported.
+fn stop_unhealthy(
+    state: &mut State,
+    config: &Config,
+    events: &Events,
+    stops: &mut Stops,
+    retries: &mut Retries,
+) {
+    let checked: Vec<&ProcessConfig> = state
+        .iter()
+        .filter(|process| process.liveness_check.is_some() || process.verify_port)
+        .filter(|process| process.is_running() && !stops.is_stopping(process))
+        .collect();
+    // Checks may take a while each, run them all at once.
+    let unhealthy: Vec<&ProcessConfig> = thread::scope(|scope| {
+        let checks: Vec<_> = checked
+            .iter()
+            .map(|process| scope.spawn(move || process.is_live()))
+            .collect();
+        checked
+            .iter()
+            .zip(checks)
+            .filter_map(|(process, check)| match check.join() {
+                Ok(false) => Some(*process),
+                _ => None,
+            })
+            .collect()
+    });
+
+    for process in unhealthy {
+        events.emit(
+            "unhealthy",
+            Some(&process.to_string()),
+            json!({
+                "liveness_check": process.liveness_check,
+                "port": process.port.filter(|_| process.verify_port),
+                "status": process.status,
+            }),
+        );
+        if process.restart != RestartPolicy::Never {
+            retries.unhealthy.insert(Stops::key(process));
+            stops.spawn(process, config, true);
+        }
+    }
+}
+
+fn restart_dependents(
+    state: &mut State,
+    index: usize,
+    config: &Config,
+    events: &Events,
+    persist: &mut Persist,
+) {
+    let name = state[index].to_string();
+    match state.restart_dependents(index, config, persist) {
+        Ok(restarted) => restarted.iter().for_each(|dependent| {
+            events.emit(
+                "restarted_dependent",
+                Some(dependent),
+                json!({ "dependency": name }),
+            )
+        }),
+        Err(err) => events.emit(
+            "failed",
+            Some(&name),
+            json!({ "error": format!("restarting dependents: {}", err) }),
+        ),
+    }
+}
+
+/// Random delay of up to `restart_jitter` between restarts, so they don't all hit at once.
+fn restart_jitter(config: &Config) -> Duration {
+    let jitter = config
+        .restart_jitter
+        .filter(|max| *max > 0)
+        .map_or(0, |max| rand::thread_rng().gen_range(0, max + 1));
+
+    Duration::from_millis(jitter)
+}
+
+/// One reconcile pass: refresh every process and restart the enabled ones that aren't running.
+fn reconcile(
+    state: &mut State,
+    config: &Config,
+    events: &Events,
+    stops: &mut Stops,
+    retries: &mut Retries,
+    persist: &mut Persist,
+) {
+    stops.collect(state, events);
+    state.update_all();
+    retries.reset_running(state);
+    stop_orphans(state, config, events, stops);
+    stop_unhealthy(state, config, events, stops, retries);
+
+    // After a mass failure, e.g. waking up from sleep, the important ones come back first.
+    let mut order: Vec<usize> = (0..state.len()).collect();
+    order.sort_by_key(|index| std::cmp::Reverse(state[*index].priority));
+    let mut restarted = 0;
+
+    for index in order {
+        if !state[index].is_enabled() || state[index].is_running() {
+            continue;
+        }
+        if stops.is_stopping(&state[index]) {
+            continue;
+        }
+
+        let name = state[index].to_string();
+        if let Some(max) = state[index].max_retries {
+            if state[index].retries >= max {
+                state[index].status = ProcessStatus::Disabled;
+                state[index].adopted = false;
+                retries.unhealthy.remove(&Stops::key(&state[index]));
+                events.emit(
+                    "gave_up",
+                    Some(&name),
+                    json!({ "retries": state[index].retries }),
+                );
+                continue;
+            }
+        }
+        if let Some(left) = retries.backing_off(&state[index]) {
+            let seconds_left = (left.as_millis() as u64).div_ceil(1000);
+            events.emit(
+                "backing_off",
+                Some(&name),
+                json!({ "retries": state[index].retries, "seconds_left": seconds_left }),
+            );
+            continue;
+        }
+        // Reaped once, later passes only see an unknown exit code.
+        let exit_code = state[index].get_pid().and_then(system::reap);
+        let failed = retries.unhealthy.remove(&Stops::key(&state[index])) || exit_code != Some(0);
+        let restart = match state[index].restart {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Never => false,
+        };
+        if !restart {
+            state[index].status = ProcessStatus::Disabled;
+            state[index].adopted = false;
+            state[index].retries = 0;
+            events.emit(
+                "exited",
+                Some(&name),
+                json!({ "exit_code": exit_code, "restart": state[index].restart }),
+            );
+            continue;
+        }
+
+        // Over max_running the process waits for a later pass, when something else has stopped.
+        if let Err(err) = state.check_capacity(index, config) {
+            events.emit("queued", Some(&name), json!({ "reason": err.to_string() }));
+            continue;
+        }
+        if let Err(err) = state.check_dependencies(index) {
+            events.emit("waiting", Some(&name), json!({ "reason": err.to_string() }));
+            continue;
+        }
+
+        if config
+            .max_restarts_per_pass
+            .is_some_and(|max| restarted >= max)
+        {
+            let reason = format!(
+                "{} restarted in this pass (max_restarts_per_pass)",
+                restarted
+            );
+            events.emit("queued", Some(&name), json!({ "reason": reason }));
+            continue;
+        }
+        if restarted > 0 {
+            thread::sleep(restart_jitter(config));
+        }
+        restarted += 1;
+
+        // The pid was reused by something else, that process is left alone.
+        if let ProcessStatus::Invalid(pid) = state[index].status {
+            events.emit("pid_taken", Some(&name), json!({ "pid": pid }));
+        }
+        state[index].restarts += 1;
+        retries.record(&mut state[index]);
+        events.emit(
+            "restarting",
+            Some(&name),
+            json!({
+                "status": state[index].status,
+                "exit_code": exit_code,
+                "restarts": state[index].restarts,
+            }),
+        );
+        if let Some(hook) = state[index].on_restart.clone() {
+            run_restart_hook(&hook, &name, exit_code, state[index].restarts, events);
+        }
+        match state.run_one(index, config, persist) {
+            Ok(outcome) => {
+                let event = match outcome {
+                    RunOutcome::Adopted(_) => "adopted",
+                    _ => "started",
+                };
+                events.emit(event, Some(&name), json!({ "status": state[index].status }));
+                restart_dependents(state, index, config, events, persist);
+            }
+            Err(err) => events.emit("failed", Some(&name), json!({ "error": err.to_string() })),
+        }
+    }
+}
+
+/// Rotates logs grown past `max_log_size`, the shared default log included.
+fn rotate_big_logs(state: &State, config: &Config, events: &Events) {
+    let max = match config.max_log_size {
+        Some(max) => max,
+        None => return,
+    };
+    let mut files: Vec<PathBuf> = state
+        .iter()
+        .flat_map(|process| process.log_files())
+        .collect();
+    files.extend(utils::get_output_path().ok().filter(|path| path.is_file()));
+    files.sort();
+    files.dedup();
+
+    for file in files {
+        let size = match file.metadata() {
+            Ok(metadata) if metadata.len() > max => metadata.len(),
+            _ => continue,
+        };
+        match utils::rotate_log(&file, MAX_LOG_SIZE_KEEP) {
+            Ok(()) => events.emit("log_rotated", None, json!({ "path": file, "size": size })),
+            Err(err) => events.emit(
+                "log_rotation_failed",
+                None,
+                json!({ "path": file, "error": err.to_string() }),
+            ),
+        }
+    }
+}
+
+/// A single reconcile pass, returning the enabled processes still down or unhealthy after it.
+pub fn watch_once(
+    state: &mut State,
+    config: &Config,
+    events: &Events,
+    persist: &mut Persist,
+) -> Result<Vec<String>, Box<dyn error::Error>> {
+    let mut stops = Stops::new();
+    let mut retries = Retries::new();
+    reconcile(state, config, events, &mut stops, &mut retries, persist);
+    // Another pass restarts whatever was stopped for failing its liveness check.
+    if !stops.pending.is_empty() {
+        stops.wait(state, events);
+        reconcile(state, config, events, &mut stops, &mut retries, persist);
+        stops.wait(state, events);
+    }
+    rotate_big_logs(state, config, events);
+    events.snapshot(state);
+    persist(state)?;
+
+    Ok(state
+        .iter()
+        .filter(|process| process.is_enabled())
+        .filter(|process| !process.is_running() || !process.is_live())
+        .map(|process| process.to_string())
+        .collect())
+}
+
+pub fn watch(
+    state: &mut State,
+    config: &Config,
+    interval: Duration,
+    pid_path: &Path,
+    events: &Events,
+    persist: &mut Persist,
+) -> MayError {
+    fs::write(pid_path, std::process::id().to_string())?;
+    system::on_signal(libc::SIGUSR2, request_upgrade);
+    on_exit_signals();
+    events.emit(
+        "watching",
+        None,
+        json!({ "processes": state.len(), "interval": interval.as_secs() }),
+    );
+
+    #[cfg(feature = "socket")]
+    let server = match config.socket_path {
+        Some(ref path) => {
+            let server = crate::socket::Server::bind(path)
+                .map_err(|err| format!("Can't listen on {:?}: {}", path, err))?;
+            events.emit("listening", None, json!({ "socket_path": server.path() }));
+            Some(server)
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "socket"))]
+    {
+        if let Some(ref path) = config.socket_path {
+            let reason = "watchman was built without the socket feature";
+            events.emit(
+                "socket_failed",
+                None,
+                json!({ "socket_path": path, "error": reason }),
+            );
+        }
+    }
+
+    let mut stops = Stops::new();
+    let mut retries = Retries::new();
+    loop {
+        reconcile(state, config, events, &mut stops, &mut retries, persist);
+        rotate_big_logs(state, config, events);
+        events.snapshot(state);
+        persist(state)?;
+
+        sleep_unless_signalled(interval, || {
+            #[cfg(feature = "socket")]
+            {
+                if let Some(ref server) = server {
+                    server.serve(state, config, events, persist);
+                }
+            }
+            // Restart what was stopped for it right away, not an interval later.
+            stops.collect(state, events) > 0
+        });
+        if EXIT_REQUESTED.load(Ordering::SeqCst) {
+            // Processes keep running, the state file shouldn't claim anything stale about them.
+            stops.wait(state, events);
+            state.update_all();
+            persist(state)?;
+            let _ = fs::remove_file(pid_path);
+            events.emit("exiting", None, json!({ "pid": std::process::id() }));
+            return Ok(());
+        }
+        if UPGRADE_REQUESTED.load(Ordering::SeqCst) {
+            // Threads don't survive the exec, let the stops finish first.
+            stops.wait(state, events);
+            // Everything the new binary needs to adopt the processes is on disk.
+            persist(state)?;
+            events.emit("upgrading", None, json!({ "pid": std::process::id() }));
+            // Only returns on failure.
+            return Err(exec_self());
+        }
+    }
+}
@@ -1,44 +1,58 @@
 use chrono::Local;
+use rand::Rng;
 use std::io::{BufRead, BufReader, Read};
 use std::marker::Send;
 use std::thread::JoinHandle;
-use std::io::{Error, ErrorKind};
-use std::sync::mpsc::{channel, Receiver};
+use std::io::Error;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
 use std::cmp;
+use std::collections::HashMap;
 use std::error;
 use std::fmt;
 use std::fs::File;
 use std::fs::OpenOptions;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use sysinfo::{Process as SysProc, ProcessExt, RefreshKind, System, SystemExt};
 
+use crate::filewatch::FileWatcher;
+
 #[derive(Debug)]
 pub struct Process {
     pub cmd: String,
     pub pid: i32,
+    /// Seconds since boot.
+    pub start_time: u64,
+    pub uid: u32,
 }
 
 fn sysproc_to_process(proc: SysProc) -> Process {
     Process {
         cmd: join(proc.cmd().to_vec()),
         pid: proc.pid(),
+        start_time: proc.start_time(),
+        uid: proc.uid,
     }
 }
 
 fn get_ext_by_pid(pid: i32) -> Option<SysProc> {
     let mut sys = System::new_with_specifics(RefreshKind::new());
     sys.refresh_process(pid);
-    sys.get_process(pid).map(|proc| proc.clone())
+    sys.get_process(pid).cloned()
 }
 
 pub fn join(parts: Vec<String>) -> String {
     let mut res = String::new();
     for part in parts {
         if !res.is_empty() {
-            res.push_str(&" ");
+            res.push(' ');
         }
         res.push_str(&part)
     }
@@ -57,8 +71,206 @@ pub fn get_by_pid(pid: i32) -> Option<Process> {
         .map(sysproc_to_process)
 }
 
+/// How long a freshly spawned process gets to show up with its command line.
+const EXEC_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Waits for a freshly spawned process to exec, a forked child has no cmd for a moment.
+///
+/// Returns `None` if the process is already gone, e.g. it crashed right away.
+pub fn wait_for_exec(pid: i32) -> Option<Process> {
+    let started = Instant::now();
+    loop {
+        let proc = get_by_pid(pid)?;
+        if !proc.cmd.is_empty() || started.elapsed() >= EXEC_TIMEOUT {
+            return Some(proc);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Collects the exit code of a child of this watchman that has exited.
+///
+/// Returns `None` for processes started by someone else or still running. Death by a
+/// signal is reported shell style, as 128 + the signal number.
+pub fn reap(pid: i32) -> Option<i32> {
+    let mut status = 0;
+    let res = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) };
+    if res != pid {
+        return None;
+    }
+
+    let status = ExitStatus::from_raw(status);
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+}
+
+/// Resource usage of a single process.
+#[derive(Debug)]
+pub struct ProcessStats {
+    pub memory_kb: u64,
+    pub uptime: Duration,
+}
+
+pub fn get_stats(pid: i32) -> Option<ProcessStats> {
+    let mut sys = System::new_with_specifics(RefreshKind::new());
+    sys.refresh_process(pid);
+    // Both in seconds since boot.
+    let uptime = sys.get_uptime();
+    sys.get_process(pid).map(|proc| ProcessStats {
+        memory_kb: proc.memory(),
+        uptime: Duration::from_secs(uptime.saturating_sub(proc.start_time())),
+    })
+}
+
+/// Resource usage of a process together with all of its descendants.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TreeUsage {
+    pub memory_kb: u64,
+    pub cpu_percent: f32,
+    /// How many processes the tree has, the root included.
+    pub processes: usize,
+}
+
+/// Usage of each of `pids` and everything they started, `None` for ones not running. CPU
+/// usage is measured over `sample`.
+pub fn get_tree_usage(pids: &[i32], sample: Duration) -> Vec<Option<TreeUsage>> {
+    let mut sys = System::new();
+    thread::sleep(sample);
+    sys.refresh_system();
+    sys.refresh_processes();
+
+    let processes = sys.get_process_list();
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    for proc in processes.values() {
+        if let Some(parent) = proc.parent() {
+            children.entry(parent).or_default().push(proc.pid());
+        }
+    }
+
+    pids.iter()
+        .map(|pid| {
+            processes.get(pid)?;
+            let mut usage = TreeUsage::default();
+            let mut pending = vec![*pid];
+            while let Some(pid) = pending.pop() {
+                if let Some(proc) = processes.get(&pid) {
+                    usage.memory_kb += proc.memory();
+                    usage.cpu_percent += proc.cpu_usage();
+                    usage.processes += 1;
+                }
+                pending.extend(children.get(&pid).into_iter().flatten());
+            }
+            Some(usage)
+        })
+        .collect()
+}
+
+/// Whether the working directory of `pid` has been deleted since it was started.
+pub fn is_cwd_deleted(pid: i32) -> bool {
+    match fs::read_link(format!("/proc/{}/cwd", pid)) {
+        Ok(cwd) => cwd.to_string_lossy().ends_with(" (deleted)"),
+        Err(_) => false,
+    }
+}
+
+/// Sends `signal` to `pid`.
+pub fn send_signal(pid: i32, signal: i32) -> io::Result<()> {
+    if unsafe { libc::kill(pid, signal) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Calls `handler` whenever this process gets `signal`.
+///
+/// The handler runs in signal context, it should do no more than set an atomic flag.
+pub fn on_signal(signal: i32, handler: extern "C" fn(i32)) {
+    unsafe {
+        libc::signal(signal, handler as libc::sighandler_t);
+    }
+}
+
+/// Signal number for names like `TERM`, `sigterm`, `SIGTERM` or plain numbers. 0 and
+/// negative numbers aren't signals, they'd stop nothing.
+pub fn parse_signal(name: &str) -> Option<i32> {
+    let name = name.trim().to_uppercase();
+    if let Ok(number) = name.parse::<i32>() {
+        return Some(number).filter(|number| *number > 0);
+    }
+    match name.strip_prefix("SIG").unwrap_or(&name) {
+        "HUP" => Some(libc::SIGHUP),
+        "INT" => Some(libc::SIGINT),
+        "QUIT" => Some(libc::SIGQUIT),
+        "KILL" => Some(libc::SIGKILL),
+        "USR1" => Some(libc::SIGUSR1),
+        "USR2" => Some(libc::SIGUSR2),
+        "TERM" => Some(libc::SIGTERM),
+        _ => None,
+    }
+}
+
+/// How often `stop_by_pid` checks whether the process is gone.
+const STOP_POLL: Duration = Duration::from_millis(50);
+
+/// How `stop_by_pid` got rid of a process.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopOutcome {
+    /// Exited on its own after the stop signal, or was already gone.
+    Exited,
+    /// Was still there after the timeout, or the signal was `KILL` to begin with.
+    Killed,
+}
+
+impl fmt::Display for StopOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopOutcome::Exited => write!(f, "exited"),
+            StopOutcome::Killed => write!(f, "killed"),
+        }
+    }
+}
+
+/// How long a killed process gets to disappear from the process list.
+const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Waits up to `timeout` for `pid` to go away, returns whether it did.
+fn wait_for_exit(pid: i32, timeout: Duration) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
+        if get_by_pid(pid).is_none() {
+            return true;
+        }
+        thread::sleep(STOP_POLL);
+    }
+    get_by_pid(pid).is_none()
+}
+
+/// What to signal to stop `pid`: the whole process group when it leads one, so children
+/// of e.g. `sh -c` go too. Watchman starts every process in a group of its own.
+fn stop_target(pid: i32) -> i32 {
+    if unsafe { libc::getpgid(pid) } == pid {
+        -pid
+    } else {
+        pid
+    }
+}
+
+/// Sends `signal` and waits up to `timeout` for the process to go away, then kills it and
+/// waits for that to take effect too.
+pub fn stop_by_pid(pid: i32, signal: i32, timeout: Duration) -> StopOutcome {
+    let target = stop_target(pid);
+    let signalled = signal != libc::SIGKILL && send_signal(target, signal).is_ok();
+    if signalled && wait_for_exit(pid, timeout) {
+        return StopOutcome::Exited;
+    }
+    let _ = send_signal(target, libc::SIGKILL);
+    wait_for_exit(pid, KILL_TIMEOUT);
+    StopOutcome::Killed
+}
+
 pub fn kill_by_pid(pid: i32) -> bool {
-    get_ext_by_pid(pid).map_or(false, |proc| proc.kill(sysinfo::Signal::Kill))
+    get_ext_by_pid(pid).is_some_and(|proc| proc.kill(sysinfo::Signal::Kill))
 }
 
 fn get_ext_by_cmd(cmd: &String) -> Option<SysProc> {
@@ -67,7 +279,7 @@ fn get_ext_by_cmd(cmd: &String) -> Option<SysProc> {
     // First we update all information of our system struct.
     system.refresh_all();
 
-    for (_pid, proc) in system.get_process_list() {
+    for proc in system.get_process_list().values() {
         if *cmd == join(proc.cmd().to_vec()) {
             return Some(proc.clone());
         }
@@ -80,6 +292,21 @@ pub fn get_by_cmd(cmd: &String) -> Option<Process> {
     get_ext_by_cmd(cmd).map(sysproc_to_process)
 }
 
+/// Every process running `cmd`, zombies left out.
+pub fn get_all_by_cmd(cmd: &str) -> Vec<Process> {
+    let mut system = sysinfo::System::new();
+    system.refresh_all();
+
+    system
+        .get_process_list()
+        .values()
+        .filter(|proc| proc.status().to_string() != "Zombie")
+        .filter(|proc| cmd == join(proc.cmd().to_vec()))
+        .cloned()
+        .map(sysproc_to_process)
+        .collect()
+}
+
 #[derive(Debug)]
 struct SysError {
     msg: String,
@@ -105,34 +332,109 @@ impl fmt::Display for SysError {
 
 impl error::Error for SysError {}
 
-fn open_log_file(path: &PathBuf) -> File {
+fn open_log_file(path: &PathBuf) -> io::Result<File> {
     let mut new_file_options = OpenOptions::new();
     let file_options = new_file_options.read(true).append(true).create(true);
 
-    file_options.open(path).unwrap()
+    file_options.open(path)
+}
+
+fn log_open_error(path: &Path, err: io::Error) -> SysError {
+    SysError::new(&format!("Can't open log {:?}: {}", path, err))
+}
+
+/// Runs `input` through `sh -c` in the foreground, output going wherever ours goes.
+pub fn run_shell(input: &str) -> io::Result<ExitStatus> {
+    Command::new("sh")
+        .arg("-c")
+        .arg(input)
+        .stdin(Stdio::null())
+        .status()
+}
+
+/// Runs `input` with `run_shell`, the error says how it failed.
+pub fn run_shell_checked(input: &str) -> Result<(), String> {
+    match run_shell(input) {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(match status.code() {
+            Some(code) => format!("failed with exit code {}", code),
+            None => "was killed by a signal".to_string(),
+        }),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Runs `input` through `sh -c`, waiting for at most `timeout`.
+///
+/// Returns `None` if the command didn't finish in time, in which case it's killed.
+pub fn run_shell_with_timeout(input: &str, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(input)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if started.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Where a spawned process writes its output.
+pub enum Output<'a> {
+    /// Appended to the log at the path.
+    Log(&'a PathBuf),
+    /// Wherever watchman's own output goes.
+    Inherit,
+    /// Nowhere, e.g. for uncaptured processes of `watch`, which would mix into its own output.
+    Null,
 }
 
+/// Spawns `input` split on whitespace, followed by `extra_args` as they are.
 pub fn run_from_string(
-    input: &String,
-    output_to: Option<&PathBuf>,
+    input: &str,
+    extra_args: &[String],
+    output_to: Output,
+    env: &[(&str, &str)],
+    cwd: Option<&Path>,
 ) -> std::result::Result<i32, Box<dyn error::Error>> {
-    let mut parts = input.trim().split_whitespace();
+    let mut parts = input.split_whitespace();
     let command = parts
         .next()
         .ok_or_else(|| SysError::new_with_invalid_command(input))?;
-    let args = parts;
+    let args = parts.map(str::to_string).chain(extra_args.iter().cloned());
 
     let (out, err) = match output_to {
-        Some(path) => {
-            let file_out = open_log_file(path);
+        Output::Log(path) => {
+            let file_out = open_log_file(path).map_err(|err| log_open_error(path, err))?;
             let file_err = file_out.try_clone()?;
             (Stdio::from(file_out), Stdio::from(file_err))
         }
-        None => (Stdio::piped(), Stdio::piped()),
+        // Nobody would read a pipe, the process writes wherever we do.
+        Output::Inherit => (Stdio::inherit(), Stdio::inherit()),
+        Output::Null => (Stdio::null(), Stdio::null()),
     };
 
-    let child = Command::new(command)
+    let mut child = Command::new(command);
+    if let Some(cwd) = cwd {
+        child.current_dir(cwd);
+    }
+    // In a group of its own a Ctrl-C meant for watchman, e.g. a `watch` in a terminal,
+    // doesn't reach the process too.
+    child.process_group(0);
+    let child = child
         .args(args)
+        .envs(env.iter().copied())
         .stdin(Stdio::null())
         .stdout(out)
         .stderr(err)
@@ -145,18 +447,166 @@ use std::thread;
 
 use std::io::{self, Write};
 
+/// How often files are checked for changes while the command runs.
+const FILE_WATCH_POLL: Duration = Duration::from_millis(250);
+
+/// Tweaks for the restart loop in [`keep_running_from_string`].
+#[derive(Debug)]
+pub struct KeepRunningOptions {
+    /// Upper bound of a random delay added to every restart, in milliseconds.
+    pub restart_jitter: Option<u64>,
+    /// Restarts the command whenever files under these paths change.
+    pub watch: Vec<PathBuf>,
+    /// How long file changes have to settle before restarting.
+    pub watch_interval: Duration,
+    /// Patterns of files not to watch, matched against file names and full paths.
+    pub ignore: Vec<String>,
+    /// Output lines buffered between the command and the log writer.
+    pub log_buffer: usize,
+    /// What happens to output once `log_buffer` is full.
+    pub overflow: OverflowPolicy,
+    /// Marks output lines with the instance of the command that wrote them.
+    pub tag: Option<LineTag>,
+}
+
+/// What output lines of `watchman run` are tagged with, to tell instances apart across
+/// restarts. Supervised processes write their logs directly, there are no lines to tag.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineTag {
+    /// Pid of the instance, e.g. `[1234]`.
+    Pid,
+    /// How many times the command has been started, e.g. `[#3]`.
+    Generation,
+}
+
+#[derive(Debug)]
+pub struct LineTagError {
+    input: String,
+}
+
+impl fmt::Display for LineTagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown line tag {:?}, expected \"pid\" or \"generation\"",
+            self.input
+        )
+    }
+}
+
+impl error::Error for LineTagError {}
+
+impl FromStr for LineTag {
+    type Err = LineTagError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "pid" => Ok(LineTag::Pid),
+            "generation" => Ok(LineTag::Generation),
+            _ => Err(LineTagError {
+                input: input.to_string(),
+            }),
+        }
+    }
+}
+
+impl LineTag {
+    fn format(self, pid: u32, generation: u32) -> String {
+        match self {
+            LineTag::Pid => format!("[{}]", pid),
+            LineTag::Generation => format!("[#{}]", generation),
+        }
+    }
+}
+
+/// What to do with output the log writer can't keep up with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop lines and count them, the command never waits on watchman.
+    Drop,
+    /// Stop reading, so the command blocks on its writes until the writer catches up.
+    Block,
+}
+
+#[derive(Debug)]
+pub struct OverflowPolicyError {
+    input: String,
+}
+
+impl fmt::Display for OverflowPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown overflow policy {:?}, expected \"drop\" or \"block\"",
+            self.input
+        )
+    }
+}
+
+impl error::Error for OverflowPolicyError {}
+
+impl FromStr for OverflowPolicy {
+    type Err = OverflowPolicyError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "drop" => Ok(OverflowPolicy::Drop),
+            "block" => Ok(OverflowPolicy::Block),
+            _ => Err(OverflowPolicyError {
+                input: input.to_string(),
+            }),
+        }
+    }
+}
+
+/// Sends captured lines to the log writer according to the overflow policy.
+#[derive(Clone)]
+struct LineSender {
+    tx: SyncSender<LogLine>,
+    overflow: OverflowPolicy,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl LineSender {
+    /// Lines sent once the receiving end is gone are thrown away.
+    fn send(&self, line: LogLine) {
+        match self.overflow {
+            OverflowPolicy::Block => {
+                let _ = self.tx.send(line);
+            }
+            OverflowPolicy::Drop => {
+                if let Err(TrySendError::Full(_)) = self.tx.try_send(line) {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+fn restart_delay(wait_time: u64, options: &KeepRunningOptions) -> Duration {
+    let jitter = options
+        .restart_jitter
+        .filter(|max| *max > 0)
+        .map_or(0, |max| rand::thread_rng().gen_range(0, max + 1));
+
+    Duration::new(wait_time, 0) + Duration::from_millis(jitter)
+}
+
 pub fn keep_running_from_string(
-    input: &String,
+    input: &str,
     output_to: Option<&PathBuf>,
+    options: &KeepRunningOptions,
 ) -> std::result::Result<i32, Box<dyn error::Error>> {
-    let mut parts = input.trim().split_whitespace();
+    let mut parts = input.split_whitespace();
     let command = parts
         .next()
         .ok_or_else(|| SysError::new_with_invalid_command(input))?;
     let args = parts;
 
     let mut wait_time = 1;
-    let mut file_out = output_to.map(|path| open_log_file(path));
+    let mut file_out = output_to
+        .map(|path| open_log_file(path).map_err(|err| log_open_error(path, err)))
+        .transpose()?;
     let mut log = move |line: LogLine| {
         let line = format!("{} {}", Local::now().format("%Y-%m-%d %H:%M:%S"), line);
         println!("{}", line);
@@ -165,7 +615,20 @@ pub fn keep_running_from_string(
         }
     };
 
+    let mut watcher = if options.watch.is_empty() {
+        None
+    } else {
+        Some(FileWatcher::new(
+            options.watch.clone(),
+            options.ignore.clone(),
+            options.watch_interval,
+        ))
+    };
+
+    let mut generation = 0;
     loop {
+        let mut files_changed = false;
+        generation += 1;
         log(LogLine::Sys(format!("Restarting {:?}", input)));
         log(LogLine::Sys(format!("res output {:?}", output_to)));
 
@@ -175,15 +638,38 @@ pub fn keep_running_from_string(
                 .stdin(Stdio::null())
                 .stdout(Stdio::piped())
                 .stderr(Stdio::piped()),
+            options.log_buffer,
+            options.overflow,
         );
 
         match run {
-            Ok((mut child, rx)) => {
+            Ok((mut child, rx, dropped)) => {
                 println!("child running {:?}", child.id());
+                let tag = options.tag.map(|tag| tag.format(child.id(), generation));
                 loop {
-                    match rx.recv() {
+                    let dropped_lines = dropped.swap(0, Ordering::Relaxed);
+                    if dropped_lines > 0 {
+                        log(LogLine::Sys(format!(
+                            "dropped {} output lines, the log buffer was full",
+                            dropped_lines
+                        )));
+                    }
+                    match rx.recv_timeout(FILE_WATCH_POLL) {
                         Ok(output) => {
-                            log(output);
+                            log(match tag {
+                                Some(ref tag) => output.tagged(tag),
+                                None => output,
+                            });
+                        }
+                        Err(RecvTimeoutError::Timeout) => {
+                            if watcher.as_mut().is_some_and(FileWatcher::should_restart) {
+                                log(LogLine::Sys("files changed, restarting".to_string()));
+                                files_changed = true;
+                                if let Err(e) = child.kill() {
+                                    log(LogLine::Sys(format!("failed to kill {:?}", e)));
+                                }
+                                break;
+                            }
                         }
                         Err(err) => {
                             if let Ok(_exit_status) = child.try_wait() {
@@ -211,7 +697,7 @@ pub fn keep_running_from_string(
                         }
                         None => {
                             wait_time = 1;
-                            log(LogLine::Sys(format!("process exited with no status")));
+                            log(LogLine::Sys("process exited with no status".to_string()));
                         }
                     },
                     Err(e) => {
@@ -228,8 +714,14 @@ pub fn keep_running_from_string(
             }
         }
 
-        log(LogLine::Sys(format!("sleeping {:?}s", wait_time)));
-        thread::sleep(Duration::new(wait_time, 0));
+        if files_changed {
+            wait_time = 1;
+            continue;
+        }
+
+        let delay = restart_delay(wait_time, options);
+        log(LogLine::Sys(format!("sleeping {:?}", delay)));
+        thread::sleep(delay);
     }
 }
 
@@ -240,6 +732,17 @@ pub enum LogLine {
     Sys(String),
 }
 
+impl LogLine {
+    /// Prefixes the command's own output with `tag`, watchman's lines are left as they are.
+    fn tagged(self, tag: &str) -> Self {
+        match self {
+            LogLine::StdOut(line) => LogLine::StdOut(format!("{} {}", tag, line)),
+            LogLine::StdErr(line) => LogLine::StdErr(format!("{} {}", tag, line)),
+            LogLine::Sys(line) => LogLine::Sys(line),
+        }
+    }
+}
+
 impl fmt::Display for LogLine {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -250,45 +753,56 @@ impl fmt::Display for LogLine {
     }
 }
 
-fn process_readable_lines<T: 'static, F: 'static>(readable: T, mut handler: F) -> JoinHandle<()>
+fn process_readable_lines<T, F>(readable: T, mut handler: F) -> JoinHandle<()>
 where
-    T: Read + Send,
-    F: FnMut(String) -> () + Send,
+    T: Read + Send + 'static,
+    F: FnMut(String) + Send + 'static,
 {
     thread::spawn(move || {
         let f = BufReader::new(readable);
-        for line in f.lines() {
-            if let Ok(line) = line {
-                handler(line);
-            }
+        for line in f.lines().map_while(Result::ok) {
+            handler(line);
         }
     })
 }
 
+/// Spawns `command`, passing its output on through a channel of at most `buffer` lines.
+///
+/// Also returns the count of lines dropped under `OverflowPolicy::Drop`, reset it after reporting.
 pub fn run_command_with_output_handler(
     command: &mut Command,
-) -> io::Result<(std::process::Child, Receiver<LogLine>)> {
+    buffer: usize,
+    overflow: OverflowPolicy,
+) -> io::Result<(std::process::Child, Receiver<LogLine>, Arc<AtomicUsize>)> {
     match command.spawn() {
         Ok(mut child) => {
             let stdout = child
                 .stdout
                 .take()
-                .ok_or(Error::new(ErrorKind::Other, "Could not take stdout"))?;
+                .ok_or_else(|| Error::other("Could not take stdout"))?;
             let stderr = child
                 .stderr
                 .take()
-                .ok_or(Error::new(ErrorKind::Other, "Could not take stderr"))?;
-            let (tx_out, rx) = channel();
+                .ok_or_else(|| Error::other("Could not take stderr"))?;
+            let (tx, rx) = sync_channel(buffer);
+            let dropped = Arc::new(AtomicUsize::new(0));
+            let tx_out = LineSender {
+                tx,
+                overflow,
+                dropped: dropped.clone(),
+            };
             let tx_err = tx_out.clone();
 
+            // Once the receiver is gone the rest is read and thrown away, the command
+            // shouldn't get a broken pipe because of us.
             let _out_handle = process_readable_lines(stdout, move |line| {
-                tx_out.send(LogLine::StdOut(line)).expect("Failed to send a stdout log line");
+                tx_out.send(LogLine::StdOut(line));
             });
             let _err_handle = process_readable_lines(stderr, move |line| {
-                tx_err.send(LogLine::StdErr(line)).expect("Failed to send a stderr log line");
+                tx_err.send(LogLine::StdErr(line));
             });
 
-            Ok((child, rx))
+            Ok((child, rx, dropped))
 
             // out_handle.join().unwrap();
             // err_handle.join().unwrap();
@@ -299,3 +813,26 @@ pub fn run_command_with_output_handler(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_signal_takes_names_in_any_case() {
+        assert_eq!(parse_signal("TERM"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("SIGTERM"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("sigterm"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("SigInt"), Some(libc::SIGINT));
+        assert_eq!(parse_signal(" hup "), Some(libc::SIGHUP));
+        assert_eq!(parse_signal("SIG"), None);
+        assert_eq!(parse_signal("NOPE"), None);
+    }
+
+    #[test]
+    fn parse_signal_takes_positive_numbers_only() {
+        assert_eq!(parse_signal("9"), Some(9));
+        assert_eq!(parse_signal("0"), None);
+        assert_eq!(parse_signal("-15"), None);
+    }
+}
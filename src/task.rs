@@ -0,0 +1,62 @@
+use colored::*;
+use std::collections::BTreeMap;
+
+use crate::state::MayError;
+use crate::state::StateError;
+use crate::system;
+
+/// Lists the tasks in the state file with their commands.
+pub fn list(tasks: &BTreeMap<String, Vec<String>>) {
+    if tasks.is_empty() {
+        println!("No tasks configured, add them under \"tasks\" in the state file");
+        return;
+    }
+    for (name, commands) in tasks {
+        println!("{}", name);
+        commands.iter().for_each(|cmd| println!("  {}", cmd));
+    }
+}
+
+/// Runs the commands of task `name` in order, stopping at the first failing one unless
+/// `keep_going`. Errors if any of them failed.
+pub fn run(tasks: &BTreeMap<String, Vec<String>>, name: &str, keep_going: bool) -> MayError {
+    let commands = tasks
+        .get(name)
+        .ok_or_else(|| StateError::new(&format!("No task named {:?}", name)))?;
+
+    let mut results: Vec<Option<Result<(), String>>> = vec![None; commands.len()];
+    for (index, cmd) in commands.iter().enumerate() {
+        println!("[{}/{}] {}", index + 1, commands.len(), cmd);
+        let result = system::run_shell_checked(cmd);
+        let failed = result.is_err();
+        results[index] = Some(result);
+        if failed && !keep_going {
+            break;
+        }
+    }
+
+    println!();
+    for (cmd, result) in commands.iter().zip(results.iter()) {
+        match result {
+            Some(Ok(())) => println!(" {} {}", "✔".green().bold(), cmd),
+            Some(Err(error)) => println!(" {} {}: {}", "✘".red().bold(), cmd, error),
+            None => println!(" {} {}: skipped", "-".dimmed(), cmd),
+        }
+    }
+
+    let failed = results
+        .iter()
+        .filter(|result| matches!(result, Some(Err(_))))
+        .count();
+    if failed > 0 {
+        let msg = format!(
+            "{} of {} commands of {:?} failed",
+            failed,
+            commands.len(),
+            name
+        );
+        return Err(Box::new(StateError::new(&msg)));
+    }
+
+    Ok(())
+}
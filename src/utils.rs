@@ -1,8 +1,14 @@
-use crate::state::State;
-use crate::state::StateTrait;
+use crate::config::{expand_template, LogNameVars};
+use crate::state::StateError;
+use crate::state::StateFile;
 use directories::ProjectDirs;
 use std::error;
 use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
 use std::path::PathBuf;
 
 fn get_config_dir() -> Result<PathBuf, Box<dyn error::Error>> {
@@ -20,19 +26,47 @@ fn get_config_dir() -> Result<PathBuf, Box<dyn error::Error>> {
     Result::Ok(default_state_path)
 }
 
-pub fn get_state_path() -> Result<PathBuf, Box<dyn error::Error>> {
+/// Where the state file is unless `--state` says otherwise, it may not exist yet.
+pub fn get_default_state_path() -> Result<PathBuf, Box<dyn error::Error>> {
     let mut default_state_path: PathBuf = get_config_dir()?;
 
     default_state_path.push(PathBuf::from("state.json"));
 
+    Result::Ok(default_state_path)
+}
+
+pub fn get_state_path() -> Result<PathBuf, Box<dyn error::Error>> {
+    let default_state_path = get_default_state_path()?;
+
     if !default_state_path.is_file() {
         println!("Creating state file: {:?}", default_state_path);
-        State::new().to_file(&default_state_path)?;
+        StateFile::default().to_file(&default_state_path)?;
     }
 
     Result::Ok(default_state_path)
 }
 
+/// Canonical form of `path`, which may not exist yet as long as its directory does.
+pub fn canonicalize_file(path: &Path) -> io::Result<PathBuf> {
+    if path.exists() {
+        return fs::canonicalize(path);
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Not a file path"))?;
+
+    Ok(fs::canonicalize(parent)?.join(file_name))
+}
+
+/// Where `watchman watch` on this state file records its pid, e.g. `state.watch.pid`.
+pub fn get_watch_pid_path(state_path: &Path) -> PathBuf {
+    state_path.with_extension("watch.pid")
+}
+
 pub fn get_output_path() -> Result<PathBuf, Box<dyn error::Error>> {
     let mut default_state_path: PathBuf = get_config_dir()?;
 
@@ -40,3 +74,313 @@ pub fn get_output_path() -> Result<PathBuf, Box<dyn error::Error>> {
 
     Result::Ok(default_state_path)
 }
+
+/// Where `log_name_template` puts logs by default, next to the state file but apart from it.
+pub fn get_log_dir() -> Result<PathBuf, Box<dyn error::Error>> {
+    let mut log_dir: PathBuf = get_config_dir()?;
+
+    log_dir.push(PathBuf::from("process-logs"));
+
+    Result::Ok(log_dir)
+}
+
+/// Expands `log_name_template` for the process named `name` running with `pid`, creating
+/// missing parent dirs.
+pub fn get_templated_output_path(
+    template: &str,
+    name: &str,
+    pid: Option<i32>,
+) -> Result<PathBuf, Box<dyn error::Error>> {
+    let log_dir = get_log_dir()?;
+    let log_dir = log_dir.to_string_lossy();
+    let name = name.replace(|c: char| c == '/' || c.is_whitespace(), "_");
+
+    let path = PathBuf::from(expand_template(
+        template,
+        &LogNameVars {
+            log_dir: &log_dir,
+            name: &name,
+            pid,
+        },
+    )?);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    Result::Ok(path)
+}
+
+/// Matches `text` against a pattern where `*` is any run of characters and `?` is any one.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// `path` with `.n` appended, e.g. `web.log.1`.
+pub fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+/// Existing rotations of a log, newest first.
+pub fn rotated_logs(path: &Path) -> Vec<PathBuf> {
+    (1..)
+        .map(|n| rotated_path(path, n))
+        .take_while(|rotated| rotated.is_file())
+        .collect()
+}
+
+/// Moves the contents of a log to `.1`, shifting older rotations up and dropping those
+/// past `keep`.
+///
+/// Copies and then truncates, processes writing to the log keep a working file handle.
+/// Lines written in between are lost.
+pub fn rotate_log(path: &Path, keep: usize) -> io::Result<()> {
+    let keep = keep.max(1);
+    let oldest = rotated_path(path, keep);
+    if oldest.is_file() {
+        fs::remove_file(oldest)?;
+    }
+    for n in (1..keep).rev() {
+        let rotated = rotated_path(path, n);
+        if rotated.is_file() {
+            fs::rename(rotated, rotated_path(path, n + 1))?;
+        }
+    }
+
+    fs::copy(path, rotated_path(path, 1))?;
+    OpenOptions::new().write(true).open(path)?.set_len(0)
+}
+
+/// A fresh supervisor session id, 16 hex digits.
+pub fn new_run_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Reads at most the last `bytes` bytes of a file, lossily decoded.
+pub fn read_tail_bytes<P: AsRef<Path>>(path: P, bytes: u64) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(bytes))).ok()?;
+
+    let mut buffer = vec![];
+    file.read_to_end(&mut buffer).ok()?;
+    Some(String::from_utf8_lossy(&buffer).into_owned())
+}
+
+/// Last `lines` lines of a file, reading no more than its last few kilobytes.
+pub fn read_tail<P: AsRef<Path>>(path: P, lines: usize) -> Option<String> {
+    const MAX_TAIL_BYTES: u64 = 8 * 1024;
+
+    let contents = read_tail_bytes(path, MAX_TAIL_BYTES)?;
+
+    let all: Vec<&str> = contents.lines().collect();
+    let tail = &all[all.len().saturating_sub(lines)..];
+    if tail.is_empty() {
+        None
+    } else {
+        Some(tail.join("\n"))
+    }
+}
+
+/// A `KEY=VALUE` env var given on the command line, the value is taken as is.
+pub fn parse_env_var(input: &str) -> Result<(String, String), String> {
+    match input.find('=') {
+        Some(at) if at > 0 => Result::Ok((input[..at].to_string(), input[at + 1..].to_string())),
+        _ => Result::Err(format!("expected KEY=VALUE, got {:?}", input)),
+    }
+}
+
+/// A `--log-buffer` size. With 0 every line would wait for the writer, and be dropped
+/// unless blocking.
+pub fn parse_log_buffer(input: &str) -> Result<usize, String> {
+    match input.parse::<usize>() {
+        Ok(0) => Result::Err("the buffer needs room for at least 1 line".to_string()),
+        Ok(size) => Result::Ok(size),
+        Err(err) => Result::Err(err.to_string()),
+    }
+}
+
+/// `KEY=VALUE` pairs of an env file. Blank lines and `#` comments are skipped, an `export `
+/// prefix and quotes around the value are allowed.
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>, Box<dyn error::Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| StateError::new(&format!("Can't read env file {:?}: {}", path, err)))?;
+
+    let mut vars = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = match line.find('=') {
+            Some(at) if at > 0 => (line[..at].trim(), line[at + 1..].trim()),
+            _ => {
+                return Err(Box::new(StateError::new(&format!(
+                    "{:?} line {}: expected KEY=VALUE, got {:?}",
+                    path,
+                    number + 1,
+                    line
+                ))))
+            }
+        };
+        let quoted = value.len() >= 2
+            && (value.starts_with('"') && value.ends_with('"')
+                || value.starts_with('\'') && value.ends_with('\''));
+        let value = if quoted {
+            &value[1..value.len() - 1]
+        } else {
+            value
+        };
+        vars.push((key.to_string(), value.to_string()));
+    }
+
+    Result::Ok(vars)
+}
+
+/// A fresh, empty directory for a test's files, removed again when dropped.
+#[cfg(test)]
+pub struct ScratchDir(PathBuf);
+
+#[cfg(test)]
+impl ScratchDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("watchman-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        ScratchDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("*.tmp", "a.tmp"));
+        assert!(glob_match("*.tmp", ".tmp"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*.tmp", "a.tmpx"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(!glob_match("abc", "ab"));
+    }
+
+    #[test]
+    fn parse_log_buffer_rejects_zero() {
+        assert_eq!(parse_log_buffer("10"), Ok(10));
+        assert!(parse_log_buffer("0").is_err());
+        assert!(parse_log_buffer("-1").is_err());
+    }
+
+    #[test]
+    fn rotate_log_shifts_rotations_and_empties_the_log() {
+        let dir = ScratchDir::new("rotate");
+        let path = dir.path().join("web.log");
+        for content in &["first", "second", "third"] {
+            fs::write(&path, content).unwrap();
+            rotate_log(&path, 2).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(
+            rotated_logs(&path),
+            vec![rotated_path(&path, 1), rotated_path(&path, 2)]
+        );
+        assert_eq!(fs::read_to_string(rotated_path(&path, 1)).unwrap(), "third");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 2)).unwrap(),
+            "second"
+        );
+        assert!(!rotated_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn rotated_logs_stops_at_a_gap() {
+        let dir = ScratchDir::new("rotated");
+        let path = dir.path().join("web.log");
+        assert!(rotated_logs(&path).is_empty());
+
+        fs::write(rotated_path(&path, 1), "").unwrap();
+        fs::write(rotated_path(&path, 3), "").unwrap();
+        assert_eq!(rotated_logs(&path), vec![rotated_path(&path, 1)]);
+    }
+
+    #[test]
+    fn read_env_file_skips_comments_and_unquotes() {
+        let dir = ScratchDir::new("env-file");
+        let path = dir.path().join(".env");
+        fs::write(
+            &path,
+            "# comment\n\nexport A=1\nB = \"two words\"\nC='x'\nD=\"\n",
+        )
+        .unwrap();
+
+        let vars = read_env_file(&path).unwrap();
+        let expected: Vec<(String, String)> =
+            vec![("A", "1"), ("B", "two words"), ("C", "x"), ("D", "\"")]
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+        assert_eq!(vars, expected);
+    }
+
+    #[test]
+    fn read_env_file_names_the_bad_line() {
+        let dir = ScratchDir::new("env-file-bad");
+        let path = dir.path().join(".env");
+        fs::write(&path, "A=1\nnot a var\n").unwrap();
+
+        let err = read_env_file(&path).unwrap_err().to_string();
+        assert!(err.contains("line 2"), "{}", err);
+        assert!(read_env_file(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn parse_env_var_splits_at_the_first_equals() {
+        assert_eq!(parse_env_var("A=1"), Ok(("A".to_string(), "1".to_string())));
+        assert_eq!(
+            parse_env_var("A=b=c"),
+            Ok(("A".to_string(), "b=c".to_string()))
+        );
+        assert_eq!(parse_env_var("A="), Ok(("A".to_string(), "".to_string())));
+        assert!(parse_env_var("=1").is_err());
+        assert!(parse_env_var("A").is_err());
+    }
+}
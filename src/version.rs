@@ -0,0 +1,30 @@
+use serde::Serialize;
+
+/// Version of the state file layout. 1 was a bare list of processes, 2 added sections.
+pub const STATE_FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub commit: Option<&'static str>,
+    pub state_format: u32,
+    pub features: Vec<&'static str>,
+}
+
+/// Optional Cargo features compiled into this build.
+fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "socket") {
+        features.push("socket");
+    }
+    features
+}
+
+pub fn info() -> VersionInfo {
+    VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: option_env!("WATCHMAN_GIT_COMMIT"),
+        state_format: STATE_FORMAT_VERSION,
+        features: features(),
+    }
+}
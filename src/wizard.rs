@@ -0,0 +1,73 @@
+use dialoguer::{theme::ColorfulTheme, Confirm, Input};
+use std::error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::import;
+use crate::state::ProcessConfig;
+use crate::state::StateFile;
+
+/// Looked for in the current directory.
+const PROCFILE: &str = "Procfile";
+
+/// Whether there's someone at the terminal to answer questions.
+pub fn is_interactive() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 && libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+/// Sets up a first state file, asking where to keep it and what to put in it. Returns where
+/// it was written.
+pub fn run(default_path: &Path) -> Result<PathBuf, Box<dyn error::Error>> {
+    let theme = ColorfulTheme::default();
+    println!("No state file yet, let's set one up. \"watchman --no-wizard\" skips this.");
+
+    let path: String = Input::with_theme(&theme)
+        .with_prompt("Where should the state file be kept?")
+        .default(default_path.to_string_lossy().to_string())
+        .interact()?;
+    let path = PathBuf::from(path);
+
+    let mut file = StateFile::default();
+    let procfile = Path::new(PROCFILE);
+    if procfile.is_file()
+        && Confirm::with_theme(&theme)
+            .with_prompt("Import the processes in ./Procfile?")
+            .default(true)
+            .interact()?
+    {
+        let imported = import::read_procfile(procfile)?;
+        println!("Imported {} processes", imported.len());
+        file.processes.extend(imported);
+    }
+
+    while Confirm::with_theme(&theme)
+        .with_prompt("Add a process?")
+        .default(file.processes.is_empty())
+        .interact()?
+    {
+        let cmd: String = Input::with_theme(&theme)
+            .with_prompt("Command to keep running")
+            .interact()?;
+        let name: String = Input::with_theme(&theme)
+            .with_prompt("Name (optional)")
+            .allow_empty(true)
+            .interact()?;
+        let name = Some(name.trim().to_string()).filter(|name| !name.is_empty());
+        file.processes
+            .push(ProcessConfig::new(cmd.trim().to_string(), name));
+    }
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)?;
+    }
+    file.to_file(&path)?;
+    println!("Wrote {:?}", path);
+    if path != default_path {
+        println!("Pass --state {:?} to use it", path);
+    }
+
+    Ok(path)
+}